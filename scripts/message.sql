@@ -4,8 +4,13 @@
 CREATE TABLE "message" (
   "id" int8 NOT NULL GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
   "user_id" int8 NOT NULL,
+  "project" int8,
   "title" varchar(64) NOT NULL,
   "content" text  NOT NULL,
+  "format" varchar(16) NOT NULL DEFAULT 'plain',
+  "overflow" varchar(16) NOT NULL DEFAULT 'split',
+  "send_time" timestamptz(6) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  "idempotency_key" varchar(255),
   "creation_time" timestamptz(6) NOT NULL DEFAULT CURRENT_TIMESTAMP
 );
 
@@ -13,3 +18,6 @@ CREATE TABLE "message" (
 -- Indexes structure for table message
 -- ----------------------------
 CREATE INDEX "idx_message_user_id" ON "message" USING btree ("user_id");
+CREATE INDEX "idx_message_user_id_id" ON "message" USING btree ("user_id", "id");
+CREATE UNIQUE INDEX "idx_message_user_id_idempotency_key" ON "message" USING btree ("user_id", "idempotency_key") WHERE "idempotency_key" IS NOT NULL;
+CREATE INDEX "idx_message_search" ON "message" USING gin (to_tsvector('simple', "title" || ' ' || "content"));
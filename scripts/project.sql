@@ -0,0 +1,22 @@
+-- ----------------------------
+-- Table structure for project
+-- ----------------------------
+CREATE TABLE "project" (
+  "id" int8 NOT NULL GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+  "user_id" int8 NOT NULL,
+  "name" varchar(64) NOT NULL,
+  "transport_types" varchar(16)[],
+  "signing_secret" varchar(64),
+  "creation_time" timestamptz(6) NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- ----------------------------
+-- Indexes structure for table project
+-- ----------------------------
+CREATE INDEX "idx_project_user_id" ON "project" USING btree ("user_id");
+
+-- ----------------------------
+-- Default projects of existing users
+-- ----------------------------
+INSERT INTO "project"("user_id", "name", "creation_time")
+SELECT "id", 'default', "creation_time" FROM "user";
@@ -0,0 +1,25 @@
+-- ----------------------------
+-- Table structure for push_key
+-- ----------------------------
+CREATE TABLE "push_key" (
+  "id" int8 NOT NULL GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+  "user_id" int8 NOT NULL,
+  "project" int8 NOT NULL,
+  "key" varchar(45) NOT NULL,
+  "last_used_time" timestamptz(6),
+  "expire_time" timestamptz(6),
+  "creation_time" timestamptz(6) NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- ----------------------------
+-- Indexes structure for table push_key
+-- ----------------------------
+CREATE INDEX "idx_push_key_user_id" ON "push_key" USING btree ("user_id");
+CREATE INDEX "idx_push_key_project" ON "push_key" USING btree ("project");
+CREATE UNIQUE INDEX "idx_push_key_key" ON "push_key" USING btree ("key");
+
+-- ----------------------------
+-- Keys of existing users, requires the default projects from project.sql
+-- ----------------------------
+INSERT INTO "push_key"("user_id", "project", "key", "creation_time")
+SELECT "user"."id", "project"."id", "user"."project_id", "user"."creation_time" FROM "user" JOIN "project" ON "project"."user_id" = "user"."id";
@@ -0,0 +1,13 @@
+-- ----------------------------
+-- Table structure for queue, only used by the postgres queue backend
+-- ----------------------------
+CREATE TABLE "queue" (
+  "task_id" int8 NOT NULL PRIMARY KEY,
+  "state" varchar(16) NOT NULL,
+  "score" int8 NOT NULL
+);
+
+-- ----------------------------
+-- Indexes structure for table queue
+-- ----------------------------
+CREATE INDEX "idx_queue_state_score" ON "queue" USING btree ("state", "score");
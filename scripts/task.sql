@@ -5,12 +5,15 @@ CREATE TABLE "task" (
   "id" int8 NOT NULL GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
   "message_id" int8 NOT NULL,
   "user_id" int8 NOT NULL,
-  "chat_id" varchar(64) NOT NULL,
+  "chat_id" varchar(1024) NOT NULL,
   "transport" int8 NOT NULL,
   "transport_type" varchar(16) NOT NULL,
   "state" varchar(16) NOT NULL,
   "retry_count" int4 NOT NULL,
+  "delivered_parts" int4 NOT NULL DEFAULT 0,
   "reason" varchar(255),
+  "last_attempt_time" timestamptz(6),
+  "delivered_time" timestamptz(6),
   "creation_time" timestamptz(6) NOT NULL DEFAULT CURRENT_TIMESTAMP
 );
 
@@ -18,3 +21,4 @@ CREATE TABLE "task" (
 -- Indexes structure for table task
 -- ----------------------------QW
 CREATE INDEX "idx_task_message_id" ON "task" USING btree ("message_id");
+CREATE INDEX "idx_task_state_id" ON "task" USING btree ("state", "id");
@@ -1,3 +1,4 @@
+use anyhow::{anyhow, Result};
 use async_std::fs::File;
 use async_std::io::ReadExt;
 use serde::Deserialize;
@@ -7,12 +8,84 @@ pub struct Server {
     pub port: u16,
     pub access_expire: i64,
     pub access_secret: String,
+    /// Lifetime of refresh tokens in seconds
+    #[serde(default = "default_refresh_expire")]
+    pub refresh_expire: i64,
+    /// `iss` and `aud` claims of issued access tokens
+    #[serde(default = "default_token_issuer")]
+    pub token_issuer: String,
+    #[serde(default = "default_token_issuer")]
+    pub token_audience: String,
+}
+
+fn default_refresh_expire() -> i64 {
+    30 * 24 * 3600
+}
+
+fn default_token_issuer() -> String {
+    String::from("sps")
+}
+
+#[derive(Clone, Deserialize)]
+pub struct Auth {
+    /// Domain that Sign-In with Ethereum messages must be issued for
+    pub domain: String,
+    /// Prefix of the URI in Sign-In with Ethereum messages
+    pub uri: String,
+    pub chain_id: u64,
+    /// JSON-RPC endpoint used to verify EIP-1271 signatures of smart contract wallets
+    pub rpc_url: Option<String>,
+    /// Lifetime of login nonces in seconds
+    #[serde(default = "default_nonce_expire")]
+    pub nonce_expire: usize,
+}
+
+fn default_nonce_expire() -> usize {
+    300
 }
 
 #[derive(Clone, Deserialize)]
 pub struct Redis {
     pub url: String,
     pub queue_name: String,
+    #[serde(default = "default_inflight_queue_name")]
+    pub inflight_queue_name: String,
+    #[serde(default = "default_dead_letter_queue_name")]
+    pub dead_letter_queue_name: String,
+}
+
+fn default_inflight_queue_name() -> String {
+    String::from("sps_inflight")
+}
+
+fn default_dead_letter_queue_name() -> String {
+    String::from("sps_dead_letter")
+}
+
+/// Storage of the delivery queue. With the postgres and memory backends the
+/// pusher, the sweeper and the dead letter commands run without redis, but the
+/// HTTP server still needs it for login nonces, refresh tokens, push signature
+/// nonces, email verification codes and rate limit buckets.
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueBackend {
+    Redis,
+    Postgres,
+    /// Process local queue, only for single instance deployments and tests
+    Memory,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct Queue {
+    pub backend: QueueBackend,
+}
+
+impl Default for Queue {
+    fn default() -> Self {
+        Queue {
+            backend: QueueBackend::Redis,
+        }
+    }
 }
 
 #[derive(Clone, Deserialize)]
@@ -24,14 +97,261 @@ pub struct Postgres {
 pub struct Telegram {
     pub url: String,
     pub token: String,
+    #[serde(default)]
+    pub mode: TelegramMode,
+    /// Public URL of `/api/telegram/webhook` registered with `setWebhook`
+    pub webhook_url: Option<String>,
+    /// Secret Telegram sends in the `X-Telegram-Bot-Api-Secret-Token` header
+    pub webhook_secret: Option<String>,
+    /// Timeout in seconds of requests that deliver messages
+    #[serde(default = "default_request_timeout")]
+    pub timeout: u64,
+}
+
+fn default_request_timeout() -> u64 {
+    10
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TelegramMode {
+    /// Long polling with `getUpdates`, only one instance may run the bot
+    #[default]
+    Polling,
+    /// Updates are delivered to the webhook route, works with multiple instances
+    Webhook,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    /// Plain connection, only meant for local SMTP sinks
+    None,
+    Starttls,
+    /// Implicit TLS
+    Tls,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct Email {
+    pub host: String,
+    pub port: u16,
+    pub tls: SmtpTls,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    /// Lifetime of email verification codes in seconds
+    #[serde(default = "default_verify_code_expire")]
+    pub verify_code_expire: usize,
+    /// Minimum number of seconds between verification emails to the same user
+    /// or the same address
+    #[serde(default = "default_bind_interval")]
+    pub bind_interval: usize,
+    /// Timeout in seconds of SMTP connections and commands
+    #[serde(default = "default_request_timeout")]
+    pub timeout: u64,
+}
+
+fn default_verify_code_expire() -> usize {
+    600
+}
+
+fn default_bind_interval() -> usize {
+    60
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct Push {
+    /// Maximum number of seconds a message can be scheduled ahead
+    pub max_delay: i64,
+    /// Maximum number of seconds a replaced push key stays valid
+    pub max_key_grace_period: i64,
+    /// Whether the push key is also accepted in the path of `/api/push/:project_id`
+    pub allow_path_key: bool,
+    /// Seconds a signed push request is accepted after its timestamp
+    pub signature_window: i64,
+    /// Seconds an idempotency key keeps returning the message it created
+    pub idempotency_retention: i64,
+    /// Maximum number of messages in a batch push
+    pub max_batch_size: usize,
+}
+
+impl Default for Push {
+    fn default() -> Self {
+        Push {
+            max_delay: 30 * 24 * 3600,
+            max_key_grace_period: 7 * 24 * 3600,
+            allow_path_key: true,
+            signature_window: 300,
+            idempotency_retention: 24 * 3600,
+            max_batch_size: 500,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct Webhook {
+    /// Request timeout in seconds, shared by the webhook, slack and discord transports
+    pub timeout: u64,
+    /// Hosts that webhooks may reach even though they resolve to loopback,
+    /// link-local or private addresses, as written in the url
+    pub allowed_hosts: Vec<String>,
+}
+
+impl Default for Webhook {
+    fn default() -> Self {
+        Webhook {
+            timeout: default_request_timeout(),
+            allowed_hosts: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct Pusher {
+    /// Number of retries before a task is moved to the dead letter queue
+    pub max_retry: i32,
+    /// Maximum age of a task in seconds before it is moved to the dead letter queue
+    pub max_task_age: i64,
+    /// Maximum number of tasks claimed from the queue at once
+    pub batch_size: usize,
+    /// Seconds a claimed task may stay in flight before it is returned to the queue
+    pub visibility_timeout: i64,
+}
+
+impl Default for Pusher {
+    fn default() -> Self {
+        Pusher {
+            max_retry: 25,
+            max_task_age: 3 * 24 * 3600,
+            batch_size: 100,
+            visibility_timeout: 300,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct Sweeper {
+    /// Seconds between two sweeps
+    pub interval: u64,
+    /// Minimum age in seconds of an unfinished task before it is considered lost
+    pub threshold: i64,
+    /// Number of tasks loaded from the database at once
+    pub batch_size: i64,
+}
+
+impl Default for Sweeper {
+    fn default() -> Self {
+        Sweeper {
+            interval: 60,
+            threshold: 300,
+            batch_size: 500,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct RateLimit {
+    pub enabled: bool,
+    /// Push requests per second a project may sustain, and the burst it may exceed that by.
+    /// A batch push costs one request per message
+    pub project_rate: f64,
+    pub project_burst: u32,
+    /// Push requests per second a source IP may sustain, and the burst it may exceed that by
+    pub ip_rate: f64,
+    pub ip_burst: u32,
+    /// Take the source IP from the `Forwarded` or `X-Forwarded-For` header, only
+    /// enable this behind a reverse proxy that sets them
+    pub trust_proxy: bool,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit {
+            enabled: true,
+            project_rate: 1.0,
+            project_burst: 30,
+            ip_rate: 5.0,
+            ip_burst: 60,
+            trust_proxy: false,
+        }
+    }
 }
 
 #[derive(Clone, Deserialize)]
 pub struct Config {
     pub server: Server,
+    pub auth: Auth,
     pub redis: Redis,
     pub postgres: Postgres,
     pub telegram: Telegram,
+    #[serde(default)]
+    pub queue: Queue,
+    pub email: Option<Email>,
+    #[serde(default)]
+    pub push: Push,
+    #[serde(default)]
+    pub webhook: Webhook,
+    #[serde(default)]
+    pub pusher: Pusher,
+    #[serde(default)]
+    pub sweeper: Sweeper,
+    #[serde(default)]
+    pub rate_limit: RateLimit,
+}
+
+impl Config {
+    /// Rejects combinations of settings the services can't work with.
+    pub fn validate(&self) -> Result<()> {
+        // Delivering a part takes up to two requests and an inline rate limit
+        // wait, the claim on the task is only extended between parts
+        let timeouts = [
+            ("telegram.timeout", Some(self.telegram.timeout)),
+            ("webhook.timeout", Some(self.webhook.timeout)),
+            (
+                "email.timeout",
+                self.email.as_ref().map(|email| email.timeout),
+            ),
+        ];
+        for (name, timeout) in timeouts {
+            if let Some(timeout) = timeout {
+                let busy =
+                    timeout.saturating_mul(2) + crate::transport::MAX_INLINE_RETRY_AFTER as u64;
+                if busy >= self.pusher.visibility_timeout.max(0) as u64 {
+                    return Err(anyhow!(
+                        "{} of {} seconds does not fit in pusher.visibility_timeout",
+                        name,
+                        timeout
+                    ));
+                }
+            }
+        }
+
+        // A zero rate would make the limiter wait forever, which it treats as an
+        // error and lets the request through
+        let limit = &self.rate_limit;
+        if limit.enabled {
+            let buckets = [
+                ("project", limit.project_rate, limit.project_burst),
+                ("ip", limit.ip_rate, limit.ip_burst),
+            ];
+            for (name, rate, burst) in buckets {
+                if !(rate.is_finite() && rate > 0.0) {
+                    return Err(anyhow!("rate_limit.{}_rate must be greater than 0", name));
+                }
+                if burst < 1 {
+                    return Err(anyhow!("rate_limit.{}_burst must be at least 1", name));
+                }
+            }
+        }
+
+        Ok(())
+    }
 }
 
 pub async fn must_load(filename: &str) -> Config {
@@ -40,5 +360,61 @@ pub async fn must_load(filename: &str) -> Config {
     let mut buf = Vec::<u8>::new();
     file.read_to_end(&mut buf).await.unwrap();
 
-    toml::from_slice(&buf).unwrap()
+    let conf: Config = toml::from_slice(&buf).unwrap();
+    if let Err(err) = conf.validate() {
+        panic!("invalid configuration, {}", err);
+    }
+
+    conf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example() -> Config {
+        toml::from_str(include_str!("../../etc/sps.toml.example")).unwrap()
+    }
+
+    #[test]
+    fn accepts_example() {
+        assert!(example().validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_timeouts_beyond_visibility_timeout() {
+        // Two requests of 10 seconds and an inline wait of 5 seconds
+        let mut conf = example();
+        conf.pusher.visibility_timeout = 26;
+        assert!(conf.validate().is_ok());
+
+        conf.pusher.visibility_timeout = 25;
+        assert!(conf.validate().is_err());
+
+        conf.pusher.visibility_timeout = 26;
+        conf.webhook.timeout = 11;
+        assert!(conf.validate().is_err());
+
+        conf.webhook.timeout = 10;
+        conf.email.as_mut().unwrap().timeout = 30;
+        assert!(conf.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_empty_rate_limit_buckets() {
+        let mut conf = example();
+        conf.rate_limit.project_rate = 0.0;
+        assert!(conf.validate().is_err());
+
+        conf.rate_limit.project_rate = f64::NAN;
+        assert!(conf.validate().is_err());
+
+        conf.rate_limit.project_rate = 1.0;
+        conf.rate_limit.ip_burst = 0;
+        assert!(conf.validate().is_err());
+
+        // Disabled limits are not used
+        conf.rate_limit.enabled = false;
+        assert!(conf.validate().is_ok());
+    }
 }
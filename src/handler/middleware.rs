@@ -1,23 +1,35 @@
+use crate::config;
+use crate::logic::{push_key, revoked_token_key};
+use crate::service::Context;
+use crate::types::Claims;
 use anyhow::Result;
+use async_std::sync::Arc;
 use hmac::{Hmac, Mac};
 use jwt::VerifyWithKey;
+use redis::AsyncCommands;
 use serde::Serialize;
 use serde_json::value::RawValue;
 use sha2::Sha256;
-use std::collections::BTreeMap;
-use tide::{Body, Middleware, Request};
+use std::ops::DerefMut;
+use tide::{Body, Middleware, Request, Response};
 
 #[derive(Clone)]
 pub struct JwtAuthMiddleware {
     key: Hmac<Sha256>,
+    issuer: String,
+    audience: String,
 }
 
 impl JwtAuthMiddleware {
-    pub fn new(access_secret: &str) -> Result<Self> {
-        let buf = Vec::from(access_secret);
+    pub fn new(conf: &config::Server) -> Result<Self> {
+        let buf = Vec::from(conf.access_secret.as_str());
         let key: Hmac<Sha256> = Hmac::new_from_slice(&buf)?;
 
-        Ok(JwtAuthMiddleware { key })
+        Ok(JwtAuthMiddleware {
+            key,
+            issuer: conf.token_issuer.clone(),
+            audience: conf.token_audience.clone(),
+        })
     }
 }
 
@@ -26,8 +38,12 @@ fn make_unauthorized_error() -> tide::Error {
 }
 
 #[tide::utils::async_trait]
-impl<State: Clone + Send + Sync + 'static> Middleware<State> for JwtAuthMiddleware {
-    async fn handle(&self, mut req: Request<State>, next: tide::Next<'_, State>) -> tide::Result {
+impl Middleware<Arc<Context>> for JwtAuthMiddleware {
+    async fn handle(
+        &self,
+        mut req: Request<Arc<Context>>,
+        next: tide::Next<'_, Arc<Context>>,
+    ) -> tide::Result {
         let auth_header = req
             .header("Authorization")
             .ok_or(make_unauthorized_error())?;
@@ -48,16 +64,31 @@ impl<State: Clone + Send + Sync + 'static> Middleware<State> for JwtAuthMiddlewa
             let result = VerifyWithKey::verify_with_key(token, &self.key)
                 .map_err(|_| make_unauthorized_error())?;
 
-            let claims: BTreeMap<String, String> = result;
-            let exp = claims.get("exp").ok_or(make_unauthorized_error())?;
-            let exp = exp.parse::<i64>().map_err(|_| make_unauthorized_error())?;
-            let username = claims.get("username").ok_or(make_unauthorized_error())?;
+            let claims: Claims = result;
+            if claims.iss != self.issuer || claims.aud != self.audience {
+                return Err(make_unauthorized_error());
+            }
 
-            if exp < chrono::Utc::now().timestamp() {
+            if claims.exp < chrono::Utc::now().timestamp() {
                 return Err(tide::Error::new(401, anyhow::anyhow!("Token has expired")));
             }
 
-            req.set_ext(username.clone());
+            let revoked: bool = {
+                let mut guard = req.state().redis_connection.lock().await?;
+                guard
+                    .deref_mut()
+                    .exists(revoked_token_key(&claims.jti))
+                    .await?
+            };
+            if revoked {
+                return Err(tide::Error::new(
+                    401,
+                    anyhow::anyhow!("Token has been revoked"),
+                ));
+            }
+
+            req.set_ext(claims.sub.clone());
+            req.set_ext(claims);
 
             return Ok(next.run(req).await);
         }
@@ -66,6 +97,175 @@ impl<State: Clone + Send + Sync + 'static> Middleware<State> for JwtAuthMiddlewa
     }
 }
 
+/// Token bucket that refills at `ARGV[1]` tokens per second up to `ARGV[2]`
+/// tokens, `ARGV[3]` is the current time in milliseconds. Takes `ARGV[4]` tokens
+/// and returns 0, or returns the milliseconds until they are available. A cost
+/// above the burst leaves the bucket in debt once it is full, so that large
+/// batches are still accepted but paid for by the requests that follow.
+const TOKEN_BUCKET_SCRIPT: &str = r#"
+local rate = tonumber(ARGV[1])
+local burst = tonumber(ARGV[2])
+local now = tonumber(ARGV[3])
+local cost = tonumber(ARGV[4])
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'ts')
+local tokens = tonumber(bucket[1]) or burst
+local ts = tonumber(bucket[2]) or now
+tokens = math.min(burst, tokens + math.max(0, now - ts) * rate / 1000)
+local required = math.min(cost, burst)
+local wait = 0
+if tokens >= required then
+    tokens = tokens - cost
+else
+    wait = math.ceil((required - tokens) * 1000 / rate)
+end
+redis.call('HMSET', KEYS[1], 'tokens', tokens, 'ts', now)
+redis.call('PEXPIRE', KEYS[1], math.ceil((burst - tokens) * 1000 / rate) + 1000)
+return wait
+"#;
+
+/// Limits push requests per project and per source IP with token buckets kept
+/// in redis.
+#[derive(Clone)]
+pub struct RateLimitMiddleware {
+    conf: config::RateLimit,
+    script: Arc<redis::Script>,
+    per_item: bool,
+}
+
+impl RateLimitMiddleware {
+    pub fn new(conf: &config::RateLimit) -> Self {
+        RateLimitMiddleware {
+            conf: conf.clone(),
+            script: Arc::new(redis::Script::new(TOKEN_BUCKET_SCRIPT)),
+            per_item: false,
+        }
+    }
+
+    /// Charges the project one token per element of the JSON array body, for
+    /// routes that push many messages at once.
+    pub fn per_item(mut self) -> Self {
+        self.per_item = true;
+        self
+    }
+
+    /// Takes `cost` tokens from the bucket, returns the milliseconds to wait if
+    /// there are not enough.
+    async fn take(
+        &self,
+        ctx: &Context,
+        key: &str,
+        rate: f64,
+        burst: u32,
+        cost: usize,
+    ) -> Result<i64> {
+        let now = chrono::Utc::now().timestamp_millis();
+        let mut guard = ctx.redis_connection.lock().await?;
+        let wait = self
+            .script
+            .key(key)
+            .arg(rate)
+            .arg(burst)
+            .arg(now)
+            .arg(cost)
+            .invoke_async(guard.deref_mut())
+            .await?;
+        Ok(wait)
+    }
+
+    /// Counts the messages in the body and puts the body back for the handler.
+    async fn count_items(&self, req: &mut Request<Arc<Context>>) -> tide::Result<usize> {
+        let body = req.take_body().into_bytes().await?;
+        let count = serde_json::from_slice::<Vec<serde::de::IgnoredAny>>(&body)
+            .map_or(1, |items| items.len().max(1));
+        req.set_body(body);
+        Ok(count)
+    }
+
+    async fn check(&self, req: &Request<Arc<Context>>, cost: usize) -> Result<i64> {
+        let ip = match self.conf.trust_proxy {
+            true => req.remote(),
+            false => req.peer_addr(),
+        };
+        // Strip the port of the peer address
+        let ip = ip.map(|addr| match addr.parse::<std::net::SocketAddr>() {
+            Ok(addr) => addr.ip().to_string(),
+            Err(_) => addr.to_string(),
+        });
+        if let Some(ip) = ip {
+            let key = format!("sps_rate_limit:ip:{}", ip);
+            let wait = self
+                .take(req.state(), &key, self.conf.ip_rate, self.conf.ip_burst, 1)
+                .await?;
+            if wait > 0 {
+                return Ok(wait);
+            }
+        }
+
+        // Unknown keys are rejected by the handler
+        let project = match push_key(req) {
+            Ok(project_id) => req
+                .state()
+                .project_model
+                .find_one_by_project_id(&project_id)
+                .await
+                .ok(),
+            Err(_) => None,
+        };
+        if let Some(project) = project {
+            let key = format!("sps_rate_limit:project:{}", project.id);
+            let wait = self
+                .take(
+                    req.state(),
+                    &key,
+                    self.conf.project_rate,
+                    self.conf.project_burst,
+                    cost,
+                )
+                .await?;
+            if wait > 0 {
+                return Ok(wait);
+            }
+        }
+
+        Ok(0)
+    }
+}
+
+#[tide::utils::async_trait]
+impl Middleware<Arc<Context>> for RateLimitMiddleware {
+    async fn handle(
+        &self,
+        mut req: Request<Arc<Context>>,
+        next: tide::Next<'_, Arc<Context>>,
+    ) -> tide::Result {
+        if !self.conf.enabled {
+            return Ok(next.run(req).await);
+        }
+
+        let cost = match self.per_item {
+            true => self.count_items(&mut req).await?,
+            false => 1,
+        };
+        let wait = match self.check(&req, cost).await {
+            Ok(wait) => wait,
+            Err(err) => {
+                // Do not turn a redis outage into an outage of the push endpoint
+                log::error!("[RateLimit] failed to check rate limit, {}", err);
+                0
+            }
+        };
+        if wait == 0 {
+            return Ok(next.run(req).await);
+        }
+
+        let retry_after = (wait + 999) / 1000;
+        let mut res = Response::new(429);
+        res.insert_header("Retry-After", retry_after.to_string());
+        res.set_error(tide::Error::new(429, anyhow::anyhow!("Too many requests")));
+        Ok(res)
+    }
+}
+
 #[derive(Default, Serialize)]
 struct JsonResponse {
     pub ok: bool,
@@ -100,8 +300,7 @@ impl<State: Clone + Send + Sync + 'static> Middleware<State> for JsonResponseMid
                 match RawValue::from_string(json) {
                     Err(err) => {
                         payload.error_code = Some(500);
-                        payload.description =
-                            Some(format!("JsonResponseMiddleware: {}", err.to_string()));
+                        payload.description = Some(format!("JsonResponseMiddleware: {}", err));
                     }
                     Ok(result) => {
                         payload.ok = true;
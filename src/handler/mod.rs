@@ -1,11 +1,13 @@
 mod middleware;
 
+use crate::config::TelegramMode;
+use crate::job::TelegramUpdateHandler;
 use crate::logic;
 use crate::service::Context;
 use anyhow::Result;
 use async_std::sync::Arc;
 use http_types::headers::HeaderValue;
-use middleware::{JsonResponseMiddleware, JwtAuthMiddleware};
+use middleware::{JsonResponseMiddleware, JwtAuthMiddleware, RateLimitMiddleware};
 use tide::security::{CorsMiddleware, Origin};
 
 pub fn register_handlers(app: &mut tide::Server<Arc<Context>>) -> Result<()> {
@@ -14,7 +16,7 @@ pub fn register_handlers(app: &mut tide::Server<Arc<Context>>) -> Result<()> {
         CorsMiddleware::new()
             .allow_methods("GET, POST, OPTIONS".parse::<HeaderValue>().unwrap())
             .allow_headers(
-                "Authorization, Content-Type"
+                "Authorization, Content-Type, X-SPS-Key, X-SPS-Timestamp, X-SPS-Signature, Idempotency-Key"
                     .parse::<HeaderValue>()
                     .unwrap(),
             )
@@ -22,19 +24,77 @@ pub fn register_handlers(app: &mut tide::Server<Arc<Context>>) -> Result<()> {
             .allow_credentials(false),
     );
 
-    let access_secret = &app.state().conf.server.access_secret;
-    let jwt_middleware = JwtAuthMiddleware::new(access_secret)?;
+    let jwt_middleware = JwtAuthMiddleware::new(&app.state().conf.server)?;
 
     // Authentication required
     app.at("/api/get_me")
-        .with(jwt_middleware)
+        .with(jwt_middleware.clone())
         .get(logic::get_me);
+    app.at("/api/messages")
+        .with(jwt_middleware.clone())
+        .get(logic::list_messages);
+    app.at("/api/messages/:message_id")
+        .with(jwt_middleware.clone())
+        .get(logic::get_message);
+    app.at("/api/auth/logout")
+        .with(jwt_middleware.clone())
+        .post(logic::logout);
+    app.at("/api/projects")
+        .with(jwt_middleware.clone())
+        .get(logic::list_projects)
+        .post(logic::create_project);
+    app.at("/api/projects/:project")
+        .with(jwt_middleware.clone())
+        .post(logic::update_project);
+    app.at("/api/projects/:project/delete")
+        .with(jwt_middleware.clone())
+        .post(logic::delete_project);
+    app.at("/api/projects/:project/signing")
+        .with(jwt_middleware.clone())
+        .post(logic::set_project_signing);
+    app.at("/api/projects/:project/rotate")
+        .with(jwt_middleware.clone())
+        .post(logic::rotate_project_id);
+    app.at("/api/email/bind")
+        .with(jwt_middleware.clone())
+        .post(logic::bind_email);
+    app.at("/api/email/verify")
+        .with(jwt_middleware.clone())
+        .post(logic::verify_email);
+    app.at("/api/webhook/bind")
+        .with(jwt_middleware.clone())
+        .post(logic::bind_webhook);
+    app.at("/api/slack/bind")
+        .with(jwt_middleware.clone())
+        .post(logic::bind_slack);
+    app.at("/api/discord/bind")
+        .with(jwt_middleware)
+        .post(logic::bind_discord);
 
     // No authentication required
+    app.at("/api/auth/nonce").get(logic::get_nonce);
     app.at("/api/auth").post(logic::auth);
-    app.at("/api/push/:project_id")
-        .get(logic::push_message)
+    app.at("/api/auth/refresh").post(logic::refresh_token);
+    if app.state().conf.telegram.mode == TelegramMode::Webhook {
+        let handler = Arc::new(TelegramUpdateHandler::new(app.state().clone())?);
+        app.at("/api/telegram/webhook").post(move |req| {
+            let handler = handler.clone();
+            async move { logic::telegram_webhook(req, &handler).await }
+        });
+    }
+    let rate_limit_middleware = RateLimitMiddleware::new(&app.state().conf.rate_limit);
+    app.at("/api/push")
+        .with(rate_limit_middleware.clone())
         .post(logic::push_message);
+    app.at("/api/push/batch")
+        .with(rate_limit_middleware.clone().per_item())
+        .post(logic::push_batch);
+    if app.state().conf.push.allow_path_key {
+        app.at("/api/push/:project_id")
+            .with(rate_limit_middleware)
+            .get(logic::push_message)
+            .post(logic::push_message);
+    }
 
     Ok(())
 }
@@ -0,0 +1,48 @@
+use crate::model;
+use crate::service::Context;
+use anyhow::{anyhow, Result};
+use async_std::sync::Arc;
+
+pub struct DeadLetterEntry {
+    pub task: model::Task,
+    pub failed_time: chrono::DateTime<chrono::Utc>,
+}
+
+/// Tasks that ran out of retries are buried in the dead letter list of the
+/// queue, so that an operator can inspect and requeue them.
+pub struct DeadLetterQueue {
+    ctx: Arc<Context>,
+}
+
+impl DeadLetterQueue {
+    pub fn new(ctx: Arc<Context>) -> Self {
+        DeadLetterQueue { ctx }
+    }
+
+    pub async fn add(&self, task_id: i64) -> Result<()> {
+        let now = chrono::Utc::now().timestamp();
+        self.ctx.queue.bury(task_id, now).await
+    }
+
+    pub async fn list(&self) -> Result<Vec<DeadLetterEntry>> {
+        let items = self.ctx.queue.buried().await?;
+
+        let mut entries = Vec::new();
+        for (task_id, failed_time) in items {
+            let task = self.ctx.task_model.find_one_by_id(task_id).await?;
+            let failed_time = chrono::DateTime::from_timestamp(failed_time, 0).unwrap_or_default();
+            entries.push(DeadLetterEntry { task, failed_time });
+        }
+
+        Ok(entries)
+    }
+
+    pub async fn requeue(&self, task_id: i64) -> Result<()> {
+        if !self.ctx.task_model.reset_failed(task_id).await? {
+            return Err(anyhow!("task {} is not in fail state", task_id));
+        }
+
+        let now = chrono::Utc::now().timestamp();
+        self.ctx.queue.unbury(task_id, now).await
+    }
+}
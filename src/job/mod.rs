@@ -1,5 +1,9 @@
+mod dead_letter;
 mod pusher;
+mod sweeper;
 mod telegram;
 
+pub use dead_letter::{DeadLetterEntry, DeadLetterQueue};
 pub use pusher::Pusher;
-pub use telegram::TelegramBot;
+pub use sweeper::Sweeper;
+pub use telegram::{TelegramBot, Update as TelegramUpdate, UpdateHandler as TelegramUpdateHandler};
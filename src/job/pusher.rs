@@ -1,89 +1,259 @@
+use super::DeadLetterQueue;
 use crate::model;
 use crate::service::Context;
 use crate::transport;
 use anyhow::{anyhow, Result};
 use async_std::{channel, channel::Receiver, channel::Sender, sync::Arc, task};
 use rand::{thread_rng, Rng};
-use redis::aio::Connection;
-use redis::{AsyncCommands, RedisResult};
-use std::ops::DerefMut;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Maximum number of parts a message is split into, the rest is truncated.
+const MAX_PARTS: usize = 20;
 
 fn new_transporter(
     ctx: &Arc<Context>,
-    name: &str,
-) -> Option<Box<dyn transport::Transport + Send + Sync>> {
-    return match name {
-        model::transport_type::TELEGRAM => Some(Box::new(transport::Telegram::new(
-            &ctx.conf.telegram.url,
-            &ctx.conf.telegram.token,
-        ))),
+    email: Option<&transport::Email>,
+    transport: &model::Transport,
+) -> Option<Box<dyn transport::Transport>> {
+    match transport.transport_type.as_str() {
+        model::transport_type::TELEGRAM => {
+            let conf = &ctx.conf.telegram;
+            match transport::Telegram::new(&conf.url, &conf.token, conf.timeout) {
+                Ok(telegram) => Some(Box::new(telegram)),
+                Err(err) => {
+                    log::error!("[Worker] failed to create telegram transport, {}", err);
+                    None
+                }
+            }
+        }
+        model::transport_type::EMAIL => {
+            email.map(|email| Box::new(email.clone()) as Box<dyn transport::Transport>)
+        }
+        model::transport_type::WEBHOOK => match &transport.secret {
+            None => None,
+            Some(secret) => match transport::Webhook::new(secret, &ctx.conf.webhook) {
+                Ok(webhook) => Some(Box::new(webhook)),
+                Err(err) => {
+                    log::error!("[Worker] failed to create webhook transport, {}", err);
+                    None
+                }
+            },
+        },
+        model::transport_type::SLACK => match transport::Slack::new(ctx.conf.webhook.timeout) {
+            Ok(slack) => Some(Box::new(slack)),
+            Err(err) => {
+                log::error!("[Worker] failed to create slack transport, {}", err);
+                None
+            }
+        },
+        model::transport_type::DISCORD => match transport::Discord::new(ctx.conf.webhook.timeout) {
+            Ok(discord) => Some(Box::new(discord)),
+            Err(err) => {
+                log::error!("[Worker] failed to create discord transport, {}", err);
+                None
+            }
+        },
         _ => None,
+    }
+}
+
+/// Splits or truncates the message to fit the maximum length of the transport.
+/// Parts of a split message are numbered in the title.
+fn split_message(
+    transporter: &dyn transport::Transport,
+    message: &model::Message,
+) -> Vec<model::Message> {
+    // Leave room for the part number in the title
+    let title = if message.title.is_empty() {
+        String::new()
+    } else {
+        format!("{} (99/99)", message.title)
+    };
+    // Escaping may make the content longer than it looks
+    let char_length = |c| transporter.char_length(c);
+    let length: usize = message.content.chars().map(char_length).sum();
+    let max = match transporter.max_length(&title) {
+        Some(max) if length > max => max,
+        _ => return vec![message.clone()],
     };
+
+    let mut contents = transport::split::fit_by(
+        &message.content,
+        &message.format,
+        &message.overflow,
+        max,
+        &char_length,
+    );
+    if contents.len() > MAX_PARTS {
+        log::warn!(
+            "[Worker] message is split into too many parts, message_id: {}, parts: {}",
+            message.id,
+            contents.len()
+        );
+        contents.truncate(MAX_PARTS);
+        // The last part may already be `max` chars long, cut it to make room
+        if let Some(last) = contents.last_mut() {
+            let marked = format!("{}{}", last, transport::split::TRUNCATION_MARKER);
+            *last = transport::split::truncate_by(&marked, &message.format, max, &char_length);
+        }
+    }
+
+    let total = contents.len();
+    contents
+        .into_iter()
+        .enumerate()
+        .map(|(index, content)| {
+            let mut part = message.clone();
+            if total > 1 && !message.title.is_empty() {
+                part.title = format!("{} ({}/{})", message.title, index + 1, total);
+            }
+            part.content = content;
+            part
+        })
+        .collect()
 }
 
 struct Worker {
     ctx: Arc<Context>,
+    dead_letter_queue: DeadLetterQueue,
+    /// Shared by all email tasks of the worker, so that SMTP connections are pooled
+    email: Option<transport::Email>,
 }
 
 impl Worker {
     fn new(ctx: Arc<Context>) -> Self {
-        Worker { ctx }
-    }
+        let email = ctx
+            .conf
+            .email
+            .as_ref()
+            .and_then(|conf| match transport::Email::new(conf) {
+                Ok(email) => Some(email),
+                Err(err) => {
+                    log::error!("[Worker] failed to create email transport, {}", err);
+                    None
+                }
+            });
 
-    async fn run(&self, receiver: Receiver<i64>) {
-        let mut conn = self
-            .ctx
-            .redis_client
-            .get_async_std_connection()
-            .await
-            .unwrap();
+        Worker {
+            dead_letter_queue: DeadLetterQueue::new(ctx.clone()),
+            ctx,
+            email,
+        }
+    }
 
+    async fn run(&self, receiver: Receiver<i64>, busy: Arc<AtomicUsize>) {
         loop {
             let data = receiver.recv().await;
-            if let Err(_) = data {
+            if data.is_err() {
                 break;
             }
 
+            busy.fetch_add(1, Ordering::AcqRel);
             let task_id = data.unwrap();
             log::debug!("[Worker] consume, task_id: {}", task_id);
+            self.consume(task_id).await;
+            busy.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
 
-            let task = self.ctx.task_model.find_one_by_id(task_id).await;
-            if let Err(err) = task {
-                log::error!(
-                    "[Worker] failed to find task by id, task_id: {}, reason: {}",
-                    task_id,
-                    err
-                );
-                continue;
+    async fn consume(&self, task_id: i64) {
+        let task = self.ctx.task_model.find_one_by_id(task_id).await;
+        if let Err(err) = task {
+            log::error!(
+                "[Worker] failed to find task by id, task_id: {}, reason: {}",
+                task_id,
+                err
+            );
+
+            // Leave the claim to expire unless the task is gone for good
+            if model::is_not_found_record_err(&err) {
+                self.ack(task_id).await;
             }
+            return;
+        }
 
-            self.push(&mut conn, task.unwrap()).await;
+        let task = task.unwrap();
+        // The task may have been handed out again after it was delivered or failed
+        if task.state == model::state::DONE || task.state == model::state::FAIL {
+            log::warn!(
+                "[Worker] skip finished task, task_id: {}, state: {}",
+                task.id,
+                task.state
+            );
+            self.ack(task.id).await;
+            return;
         }
+
+        self.push(task).await;
     }
 
-    async fn push(&self, conn: &mut Connection, task: model::Task) {
+    async fn push(&self, task: model::Task) {
         let message = self.ctx.message_model.find_one_by_id(task.message_id).await;
         if let Err(err) = message {
-            self.retry_task(conn, &task, &err.to_string()).await;
+            self.retry_task(&task, task.creation_time, &err.to_string())
+                .await;
             return;
         }
+        let message = message.unwrap();
+
+        let transport = match self
+            .ctx
+            .transport_model
+            .find_one_by_id(task.transport)
+            .await
+        {
+            Ok(transport) => transport,
+            Err(err) => {
+                match model::is_not_found_record_err(&err) {
+                    true => self.skip_task(task.id, "transport not found").await,
+                    false => {
+                        self.retry_task(&task, message.send_time, &err.to_string())
+                            .await
+                    }
+                }
+                return;
+            }
+        };
 
-        let transporter = new_transporter(&self.ctx, &task.transport_type);
+        let transporter = new_transporter(&self.ctx, self.email.as_ref(), &transport);
         if transporter.is_none() {
             self.skip_task(task.id, "transport not found").await;
             return;
         }
 
-        let message = message.unwrap();
         let transporter = transporter.unwrap();
 
-        let result = transporter
-            .push(&task.chat_id, &message.title, &message.content)
-            .await;
-        if let Err(err) = result {
-            self.retry_task(conn, &task, &err.to_string()).await;
-            return;
+        let parts = split_message(transporter.as_ref(), &message);
+        let total = parts.len();
+        for (index, part) in parts.iter().enumerate().skip(task.delivered_parts as usize) {
+            let result = transporter.deliver(&task, part).await;
+            if let Err(err) = result {
+                self.handle_delivery_error(&task, message.send_time, err)
+                    .await;
+                return;
+            }
+
+            // Parts that were delivered are not sent again when a later part fails
+            if index + 1 < total {
+                let result = self
+                    .ctx
+                    .task_model
+                    .update_delivered_parts(task.id, (index + 1) as i32)
+                    .await;
+                if let Err(err) = result {
+                    log::error!(
+                        "[Worker] failed to update delivered parts, task_id: {}, {}",
+                        task.id,
+                        err
+                    );
+                }
+
+                // Keep the task invisible to other workers while the next part
+                // is delivered
+                if !self.extend_claim(task.id).await {
+                    return;
+                }
+            }
         }
 
         log::debug!(
@@ -99,6 +269,41 @@ impl Worker {
                 err
             )
         }
+
+        self.ack(task.id).await;
+    }
+
+    /// Extends the claim on a task. Returns false if the claim has expired, the
+    /// task may then be handed out to another worker that continues with the
+    /// parts that were not delivered yet.
+    async fn extend_claim(&self, task_id: i64) -> bool {
+        let now = chrono::Utc::now().timestamp();
+        match self.ctx.queue.extend(task_id, now).await {
+            Ok(true) => true,
+            Ok(false) => {
+                log::warn!("[Worker] claim on task expired, task_id: {}", task_id);
+                false
+            }
+            Err(err) => {
+                log::error!(
+                    "[Worker] failed to extend claim on task, task_id: {}, reason: {}",
+                    task_id,
+                    err
+                );
+                true
+            }
+        }
+    }
+
+    /// Releases the claim on a task that needs no further delivery attempts.
+    async fn ack(&self, task_id: i64) {
+        if let Err(err) = self.ctx.queue.ack(task_id).await {
+            log::error!(
+                "[Worker] failed to release task, task_id: {}, reason: {}",
+                task_id,
+                err
+            );
+        }
     }
 
     async fn skip_task(&self, task_id: i64, reason: &str) {
@@ -107,9 +312,171 @@ impl Worker {
             task_id,
             reason
         );
+
+        self.ack(task_id).await;
     }
 
-    async fn retry_task(&self, conn: &mut Connection, task: &model::Task, reason: &str) {
+    async fn fail_task(&self, task: &model::Task, reason: &str) {
+        log::error!(
+            "[Worker] task failed, task_id: {}, retry_count: {}, reason: {}",
+            task.id,
+            task.retry_count,
+            reason
+        );
+
+        if let Err(err) = self.ctx.task_model.set_fail(task.id, reason).await {
+            log::error!(
+                "[Worker] failed to set task state as fail, task_id: {}, {}",
+                task.id,
+                err
+            );
+            return;
+        }
+
+        if let Err(err) = self.dead_letter_queue.add(task.id).await {
+            log::error!(
+                "[Worker] failed to add task to dead letter queue, task_id: {}, {}",
+                task.id,
+                err
+            );
+        }
+    }
+
+    /// Fails, retries or reschedules the task depending on the kind of error
+    /// the transport returned.
+    async fn handle_delivery_error(
+        &self,
+        task: &model::Task,
+        due_time: chrono::DateTime<chrono::Utc>,
+        err: anyhow::Error,
+    ) {
+        match err.downcast_ref::<transport::DeliveryError>() {
+            Some(transport::DeliveryError::Permanent(reason)) => self.fail_task(task, reason).await,
+            Some(transport::DeliveryError::RateLimited { retry_after, .. }) => {
+                self.reschedule_task(task, due_time, &err.to_string(), *retry_after)
+                    .await
+            }
+            Some(transport::DeliveryError::ChatMigrated { chat_id, .. }) => {
+                self.migrate_task(task, chat_id).await
+            }
+            _ => self.retry_task(task, due_time, &err.to_string()).await,
+        }
+    }
+
+    /// Moves the task and the transports of its chat to the chat it migrated to,
+    /// and delivers it there right away. The attempt is not counted as a retry.
+    async fn migrate_task(&self, task: &model::Task, chat_id: &str) {
+        log::info!(
+            "[Worker] chat migrated, task_id: {}, chat_id: {}, new chat_id: {}",
+            task.id,
+            task.chat_id,
+            chat_id
+        );
+
+        let result = self
+            .ctx
+            .transport_model
+            .update_chat_id_by_chat_id(&task.transport_type, &task.chat_id, chat_id)
+            .await;
+        if let Err(err) = result {
+            log::error!(
+                "[Worker] failed to migrate transport chat, task_id: {}, reason: {}",
+                task.id,
+                err
+            );
+            return;
+        }
+
+        // Also moves other unfinished tasks of the chat
+        let result = self
+            .ctx
+            .task_model
+            .update_chat_id_by_chat_id(&task.transport_type, &task.chat_id, chat_id)
+            .await;
+        if let Err(err) = result {
+            log::error!(
+                "[Worker] failed to migrate task chat, task_id: {}, reason: {}",
+                task.id,
+                err
+            );
+            return;
+        }
+
+        let now = chrono::Utc::now().timestamp();
+        if let Err(err) = self.ctx.queue.nack(task.id, now).await {
+            log::error!(
+                "[Worker] failed to reschedule task, task_id: {}, reason: {}",
+                task.id,
+                err
+            );
+        }
+    }
+
+    /// Schedules the next delivery attempt in `retry_after` seconds, as asked by
+    /// a rate limited transport. The attempt is not counted as a retry.
+    async fn reschedule_task(
+        &self,
+        task: &model::Task,
+        due_time: chrono::DateTime<chrono::Utc>,
+        reason: &str,
+        retry_after: u64,
+    ) {
+        let now = chrono::Utc::now().timestamp();
+        if now - due_time.timestamp() >= self.ctx.conf.pusher.max_task_age {
+            self.fail_task(task, reason).await;
+            return;
+        }
+
+        log::warn!(
+            "[Worker] reschedule task, task_id: {}, reason: {}",
+            task.id,
+            reason
+        );
+
+        let retry_after = i64::try_from(retry_after).unwrap_or(i64::MAX);
+        let result = self
+            .ctx
+            .queue
+            .nack(task.id, now.saturating_add(retry_after))
+            .await;
+        if let Err(err) = result {
+            log::error!(
+                "[Worker] failed to reschedule task, task_id: {}, reason: {}",
+                task.id,
+                err
+            );
+            return;
+        }
+
+        let result = self
+            .ctx
+            .task_model
+            .update_rate_limited_state(task.id, reason)
+            .await;
+        if let Err(err) = result {
+            log::error!(
+                "[Worker] failed to update retry state, task_id: {}, reason: {}",
+                task.id,
+                err
+            );
+        }
+    }
+
+    /// Schedules the next delivery attempt, or fails the task once it exceeds the
+    /// retry budget. The age of the task is counted from `due_time`.
+    async fn retry_task(
+        &self,
+        task: &model::Task,
+        due_time: chrono::DateTime<chrono::Utc>,
+        reason: &str,
+    ) {
+        let now = chrono::Utc::now().timestamp();
+        let conf = &self.ctx.conf.pusher;
+        if task.retry_count >= conf.max_retry || now - due_time.timestamp() >= conf.max_task_age {
+            self.fail_task(task, reason).await;
+            return;
+        }
+
         log::warn!(
             "[Worker] retry task, task_id: {}, reason: {}",
             task.id,
@@ -120,11 +487,7 @@ impl Worker {
         // Formula taken from https://github.com/mperham/sidekiq.
         let s = task.retry_count.pow(4) + 15 + (r * (task.retry_count + 1));
 
-        let now = chrono::Utc::now().timestamp();
-        let key = &self.ctx.conf.redis.queue_name;
-        let result = conn
-            .zadd::<_, _, _, i32>(key.as_str(), task.id, now + i64::from(s))
-            .await;
+        let result = self.ctx.queue.nack(task.id, now + i64::from(s)).await;
         if let Err(err) = result {
             log::error!(
                 "[Worker] failed to retry task, task_id: {}, reason: {}",
@@ -145,54 +508,65 @@ impl Worker {
                 task.id,
                 err
             );
-            return;
         }
     }
 }
 
+/// Claims only as many tasks as there are idle workers, so that claimed tasks
+/// never wait in memory until their visibility timeout expires.
 struct Poller {
     ctx: Arc<Context>,
-    next: usize,
-    senders: Vec<Sender<i64>>,
+    workers: usize,
+    busy: Arc<AtomicUsize>,
+    sender: Sender<i64>,
 }
 
 impl Poller {
     fn new(ctx: Arc<Context>, workers: u32) -> Self {
-        let mut senders = Vec::<Sender<i64>>::new();
+        let workers = workers as usize;
+        let busy = Arc::new(AtomicUsize::new(0));
+        let (sender, receiver) = channel::bounded(workers);
         for _ in 0..workers {
-            let (sender, receiver) = channel::unbounded();
-            senders.push(sender);
-
             let ctx = ctx.clone();
+            let receiver = receiver.clone();
+            let busy = busy.clone();
             task::spawn(async move {
                 let worker = Worker::new(ctx);
-                worker.run(receiver).await;
+                worker.run(receiver, busy).await;
             });
         }
 
         Poller {
             ctx,
-            next: 0,
-            senders,
+            workers,
+            busy,
+            sender,
         }
     }
 
-    async fn start_polling(&mut self, running: &AtomicBool) {
+    /// Number of workers that are neither delivering nor have a task waiting.
+    fn idle_workers(&self) -> usize {
+        self.workers
+            .saturating_sub(self.busy.load(Ordering::Acquire) + self.sender.len())
+    }
+
+    async fn start_polling(&self, running: &AtomicBool) {
         running.store(true, Ordering::Release);
-        let queue_name = self.ctx.conf.redis.queue_name.clone();
+        let batch_size = self.ctx.conf.pusher.batch_size;
 
         log::info!("[Pusher] start polling");
 
         while running.load(Ordering::Acquire) {
-            let ts = chrono::Utc::now().timestamp();
-            let mut guard = self.ctx.redis_connection.lock().await;
+            let idle = self.idle_workers();
+            if idle == 0 {
+                task::sleep(std::time::Duration::from_millis(100)).await;
+                continue;
+            }
 
-            let result: RedisResult<Vec<i64>> = guard
-                .deref_mut()
-                .zrangebyscore(queue_name.as_str(), 0, ts)
-                .await;
+            let ts = chrono::Utc::now().timestamp();
+            let result = self.ctx.queue.claim_due(ts, batch_size.min(idle)).await;
             if let Err(err) = result {
-                log::error!("[Pusher] redis read error, {}", err);
+                log::error!("[Pusher] queue read error, {}", err);
 
                 task::sleep(std::time::Duration::from_secs(1)).await;
                 continue;
@@ -204,16 +578,8 @@ impl Poller {
                 continue;
             }
 
-            let result: RedisResult<i64> = guard
-                .deref_mut()
-                .zrembyscore(queue_name.as_str(), 0, ts)
-                .await;
-            if let Err(err) = result {
-                log::error!("[Pusher] redis write error, {}", err);
-            }
-
             for task_id in task_ids {
-                let result = self.senders[self.next].send(task_id).await;
+                let result = self.sender.send(task_id).await;
                 if let Err(err) = result {
                     log::error!(
                         "[Pusher] failed to assign task, task_id: {}, {}",
@@ -221,17 +587,10 @@ impl Poller {
                         err
                     );
                 }
-
-                self.next += 1;
-                if self.next >= self.senders.len() {
-                    self.next = 0;
-                }
             }
         }
 
-        for sender in &self.senders {
-            sender.close();
-        }
+        self.sender.close();
 
         log::info!("[Pusher] stop polling");
     }
@@ -265,13 +624,16 @@ impl Pusher {
     pub fn start(&self) -> Result<()> {
         let ctx = self.ctx.clone();
         let state = self.state.clone();
-        if let Err(_) = state.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed) {
+        if state
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
             return Err(anyhow!("already running"));
         }
 
         let workers = self.workers;
         task::spawn(async move {
-            let mut poller = Poller::new(ctx, workers);
+            let poller = Poller::new(ctx, workers);
             poller.start_polling(state.as_ref()).await;
         });
 
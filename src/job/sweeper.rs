@@ -0,0 +1,115 @@
+use crate::model;
+use crate::service::Context;
+use anyhow::{anyhow, Result};
+use async_std::{sync::Arc, task};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+struct Scanner {
+    ctx: Arc<Context>,
+}
+
+impl Scanner {
+    fn new(ctx: Arc<Context>) -> Self {
+        Scanner { ctx }
+    }
+
+    async fn sweep(&self) -> Result<usize> {
+        let conf = &self.ctx.conf;
+        let now = chrono::Utc::now();
+        let before = now - chrono::Duration::seconds(conf.sweeper.threshold);
+
+        let mut after_id = 0;
+        let mut recovered = 0;
+        loop {
+            let tasks = self
+                .ctx
+                .task_model
+                .find_unfinished_before(before, after_id, conf.sweeper.batch_size)
+                .await?;
+            if tasks.is_empty() {
+                break;
+            }
+
+            for (task_id, send_time) in &tasks {
+                // A worker may have finished the task since the page was read
+                let task = match self.ctx.task_model.find_one_by_id(*task_id).await {
+                    Ok(task) => task,
+                    Err(err) if model::is_not_found_record_err(&err) => continue,
+                    Err(err) => return Err(err),
+                };
+                if task.state != model::state::PENDING && task.state != model::state::RETRYING {
+                    continue;
+                }
+
+                let at = std::cmp::max(now, *send_time).timestamp();
+                let added = self.ctx.queue.recover(*task_id, at).await?;
+                if added {
+                    log::warn!("[Sweeper] recovered lost task, task_id: {}", task_id);
+                    recovered += 1;
+                }
+            }
+
+            after_id = tasks.last().unwrap().0;
+        }
+
+        Ok(recovered)
+    }
+
+    async fn start_sweeping(&self, running: &AtomicBool) {
+        running.store(true, Ordering::Release);
+        let interval = std::time::Duration::from_secs(self.ctx.conf.sweeper.interval);
+
+        log::info!("[Sweeper] start sweeping");
+
+        while running.load(Ordering::Acquire) {
+            match self.sweep().await {
+                Ok(recovered) => log::info!("[Sweeper] sweep finished, recovered: {}", recovered),
+                Err(err) => log::error!("[Sweeper] failed to sweep, {}", err),
+            }
+
+            task::sleep(interval).await;
+        }
+
+        log::info!("[Sweeper] stop sweeping");
+    }
+}
+
+/// Re-enqueues unfinished tasks that have no entry in the queue, for example
+/// after redis lost its data.
+pub struct Sweeper {
+    ctx: Arc<Context>,
+    state: Arc<AtomicBool>,
+}
+
+impl Drop for Sweeper {
+    fn drop(&mut self) {
+        self.state.store(false, Ordering::Release);
+    }
+}
+
+impl Sweeper {
+    pub fn new(ctx: Arc<Context>) -> Self {
+        Sweeper {
+            ctx,
+            state: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn start(&self) -> Result<()> {
+        let ctx = self.ctx.clone();
+        let state = self.state.clone();
+        if state
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return Err(anyhow!("already running"));
+        }
+
+        task::spawn(async move {
+            let scanner = Scanner::new(ctx);
+            scanner.start_sweeping(state.as_ref()).await;
+        });
+
+        Ok(())
+    }
+}
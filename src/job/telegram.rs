@@ -1,15 +1,18 @@
+use crate::config::TelegramMode;
 use crate::model;
 use crate::service::Context;
 use crate::transport::{Telegram, Transport};
 use anyhow::{anyhow, Result};
-use async_std::{sync::Arc, task};
+use async_std::sync::{Arc, Mutex};
+use async_std::task;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::sync::atomic::{AtomicBool, Ordering};
 
 #[derive(Default, Debug, Serialize)]
 struct GetUpdates {
     #[serde(skip_serializing_if = "Option::is_none")]
-    offset: Option<i32>,
+    offset: Option<i64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     limit: Option<i32>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -18,15 +21,38 @@ struct GetUpdates {
     allowed_updates: Option<Vec<String>>,
 }
 
+#[derive(Debug, Serialize)]
+struct SetWebhook<'a> {
+    url: &'a str,
+    secret_token: &'a str,
+    allowed_updates: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct DeleteWebhook {}
+
+#[derive(Debug, Serialize)]
+struct GetMe {}
+
+/// Updates the bot subscribes to.
+const ALLOWED_UPDATES: &[&str] = &["message", "channel_post", "my_chat_member"];
+
+fn allowed_updates() -> Vec<String> {
+    ALLOWED_UPDATES.iter().map(|s| s.to_string()).collect()
+}
+
 #[derive(Deserialize)]
 pub struct Update {
-    pub update_id: i32,
+    pub update_id: i64,
     pub message: Option<Message>,
+    pub channel_post: Option<Message>,
+    pub my_chat_member: Option<ChatMemberUpdated>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Chat {
-    pub id: i32,
+    /// Supergroup and channel ids exceed 32 bits
+    pub id: i64,
     #[serde(rename = "type")]
     pub chat_type: String,
     pub username: Option<String>,
@@ -34,78 +60,153 @@ pub struct Chat {
 
 #[derive(Debug, Deserialize)]
 pub struct Message {
-    pub message_id: i32,
+    #[allow(dead_code)]
+    pub message_id: i64,
     pub chat: Chat,
     pub text: Option<String>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct User {
+    pub username: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatMember {
+    pub status: String,
+}
+
+/// Sent when the bot is added to or removed from a chat.
+#[derive(Debug, Deserialize)]
+pub struct ChatMemberUpdated {
+    pub chat: Chat,
+    pub new_chat_member: ChatMember,
+}
+
+/// Splits `/start <arg>` or `/start@botname <arg>` into the bot name and argument.
+fn parse_start_command(text: &str) -> Option<(Option<&str>, &str)> {
+    let (command, arg) = text.trim().split_once(char::is_whitespace)?;
+    let bot = match command.strip_prefix("/start")? {
+        "" => None,
+        suffix => Some(suffix.strip_prefix('@')?),
+    };
+
+    let arg = arg.trim();
+    if arg.is_empty() {
+        return None;
+    }
+
+    Some((bot, arg))
+}
+
 #[derive(Deserialize)]
-struct ResponsePayload {
+struct ResponsePayload<T> {
     ok: bool,
     description: Option<String>,
-    result: Option<Vec<Update>>,
+    result: Option<T>,
 }
 
-struct Poller {
-    uri: String,
+/// Calls a method of the Telegram Bot API.
+async fn call_api<D: Serialize, T: DeserializeOwned>(
+    ctx: &Context,
+    method: &str,
+    data: &D,
+) -> Result<T> {
+    let uri = format!(
+        "{}bot{}/{}",
+        ctx.conf.telegram.url, ctx.conf.telegram.token, method
+    );
+
+    let mut res = match surf::post(&uri).body_json(data) {
+        Ok(req) => match req.await {
+            Ok(res) => res,
+            Err(err) => return Err(err.into_inner()),
+        },
+        Err(err) => return Err(err.into_inner()),
+    };
+
+    return match res.body_json::<ResponsePayload<T>>().await {
+        Ok(ResponsePayload {
+            ok: false,
+            description: Some(description),
+            ..
+        }) => Err(anyhow!(description)),
+        Ok(response) => match response.result {
+            None => Err(anyhow!("invalid result")),
+            Some(result) => Ok(result),
+        },
+        Err(err) => Err(err.into_inner()),
+    };
+}
+
+/// Handles updates from the bot, no matter if they were polled or delivered to
+/// the webhook.
+pub struct UpdateHandler {
     ctx: Arc<Context>,
-    offset: i32,
     tg_transport: Telegram,
+    username: Mutex<Option<String>>,
 }
 
-impl Poller {
-    fn new(ctx: Arc<Context>) -> Self {
-        let base_url = &ctx.conf.telegram.url;
-        let access_token = &ctx.conf.telegram.token;
-        let uri = format!("{}bot{}/getUpdates", base_url, access_token);
-        let tg_transport = Telegram::new(base_url.as_str(), access_token.as_str());
-
-        Poller {
+impl UpdateHandler {
+    pub fn new(ctx: Arc<Context>) -> Result<Self> {
+        let conf = &ctx.conf.telegram;
+        let tg_transport = Telegram::new(&conf.url, &conf.token, conf.timeout)?;
+        Ok(UpdateHandler {
             ctx,
-            uri,
-            offset: 0,
             tg_transport,
-        }
+            username: Mutex::new(None),
+        })
     }
 
-    async fn get_updates(&mut self) -> Result<Vec<Update>> {
-        let mut data = GetUpdates::default();
-        data.limit = Some(100);
-        data.timeout = Some(5);
-        data.allowed_updates = Some(vec![String::from("message")]);
-
-        if self.offset > 0 {
-            data.offset = Some(self.offset);
+    pub async fn handle_update(&self, update: &Update) -> Result<()> {
+        if let Some(message) = &update.message {
+            return self.handle_message(message).await;
+        }
+        if let Some(post) = &update.channel_post {
+            return self.handle_message(post).await;
+        }
+        if let Some(member) = &update.my_chat_member {
+            return self.handle_chat_member(member).await;
         }
 
-        log::info!("[TelegramBot] {:?}", data);
+        Ok(())
+    }
 
-        let mut res = match surf::post(&self.uri).body_json(&data) {
-            Ok(req) => match req.await {
-                Ok(res) => res,
-                Err(err) => return Err(err.into_inner()),
-            },
-            Err(err) => return Err(err.into_inner()),
-        };
+    /// Returns the username of the bot, it is looked up once.
+    async fn bot_username(&self) -> Result<String> {
+        let mut username = self.username.lock().await;
+        if username.is_none() {
+            let me: User = call_api(&self.ctx, "getMe", &GetMe {}).await?;
+            *username = me.username;
+        }
 
-        return match res.body_json::<ResponsePayload>().await {
-            Ok(ResponsePayload {
-                ok: false,
-                description: Some(description),
-                ..
-            }) => Err(anyhow!(description)),
-            Ok(response) => match response.result {
-                None => Err(anyhow!("invalid result")),
-                Some(updates) => {
-                    if !updates.is_empty() {
-                        self.offset = updates.last().unwrap().update_id + 1;
-                    }
-
-                    Ok(updates)
-                }
-            },
-            Err(err) => Err(err.into_inner()),
-        };
+        username
+            .clone()
+            .ok_or_else(|| anyhow!("bot has no username"))
+    }
+
+    async fn handle_chat_member(&self, update: &ChatMemberUpdated) -> Result<()> {
+        let chat_id = update.chat.id.to_string();
+        match update.new_chat_member.status.as_str() {
+            // Notifications can no longer be delivered to the chat
+            "left" | "kicked" => {
+                log::info!("[TelegramBot] removed from chat {}", chat_id);
+                self.ctx
+                    .transport_model
+                    .update_connected_by_chat_id(model::transport_type::TELEGRAM, &chat_id, false)
+                    .await
+            }
+            "member" | "administrator" if update.chat.chat_type != "private" => {
+                log::info!("[TelegramBot] added to chat {}", chat_id);
+                let username = self.bot_username().await?;
+                let hint = match update.chat.chat_type.as_str() {
+                    "channel" => String::from("Post /start <open_id> in this channel to receive notifications from simple push service here."),
+                    _ => format!("Send /start@{} <open_id> to receive notifications from simple push service in this chat.", username),
+                };
+                self.tg_transport.push(&chat_id, "", &hint).await
+            }
+            _ => Ok(()),
+        }
     }
 
     async fn handle_message(&self, message: &Message) -> Result<()> {
@@ -114,11 +215,18 @@ impl Poller {
         }
 
         let text = message.text.as_ref().unwrap();
-        if !text.starts_with("/start ") {
-            return Ok(());
+        let (bot, open_id) = match parse_start_command(text) {
+            None => return Ok(()),
+            Some(command) => command,
+        };
+
+        // Commands in groups may be addressed to another bot
+        if let Some(bot) = bot {
+            if !bot.eq_ignore_ascii_case(&self.bot_username().await?) {
+                return Ok(());
+            }
         }
 
-        let open_id = &text["/start ".len()..];
         let chat_id = message.chat.id.to_string();
         let username = message.chat.username.clone();
         let user = self.ctx.user_model.find_one_by_open_id(open_id).await?;
@@ -129,6 +237,20 @@ impl Poller {
             .find_one_by_user_id_type(user.id, model::transport_type::TELEGRAM)
             .await;
         match result {
+            // A user has one Telegram transport, binding another chat would
+            // silently take notifications away from the one that is bound
+            Ok(transport)
+                if transport.connected
+                    && transport.chat_id.is_some()
+                    && transport.chat_id.as_deref() != Some(chat_id.as_str()) =>
+            {
+                self.tg_transport.push(
+                    chat_id.as_str(),
+                    "",
+                    "Your Telegram transport already delivers to another chat. Remove the bot from that chat, or block it there if it is a private chat, then send /start again here.",
+                ).await?;
+                return Ok(());
+            }
             Ok(_) => {
                 self.ctx
                     .transport_model
@@ -139,6 +261,10 @@ impl Poller {
                         chat_id.as_str(),
                     )
                     .await?;
+                self.ctx
+                    .transport_model
+                    .update_connected(user.id, model::transport_type::TELEGRAM, true)
+                    .await?;
             }
             Err(err) => match model::is_not_found_record_err(&err) {
                 true => {
@@ -161,29 +287,76 @@ impl Poller {
 
         Ok(())
     }
+}
+
+struct Poller {
+    ctx: Arc<Context>,
+    offset: i64,
+    handler: UpdateHandler,
+}
+
+impl Poller {
+    fn new(ctx: Arc<Context>) -> Result<Self> {
+        Ok(Poller {
+            handler: UpdateHandler::new(ctx.clone())?,
+            ctx,
+            offset: 0,
+        })
+    }
+
+    async fn get_updates(&mut self) -> Result<Vec<Update>> {
+        let data = GetUpdates {
+            offset: if self.offset > 0 {
+                Some(self.offset)
+            } else {
+                None
+            },
+            limit: Some(100),
+            timeout: Some(5),
+            allowed_updates: Some(allowed_updates()),
+        };
+
+        log::info!("[TelegramBot] {:?}", data);
+
+        let updates: Vec<Update> = call_api(&self.ctx, "getUpdates", &data).await?;
+        if !updates.is_empty() {
+            self.offset = updates.last().unwrap().update_id + 1;
+        }
+
+        Ok(updates)
+    }
 
     async fn start_polling(&mut self, running: &AtomicBool) {
         running.store(true, Ordering::Release);
 
         log::info!("[TelegramBot] start polling");
 
+        // getUpdates is rejected while a webhook is set
+        if let Err(err) = call_api::<_, bool>(&self.ctx, "deleteWebhook", &DeleteWebhook {}).await {
+            log::error!("[TelegramBot] failed to deleteWebhook, {}", err);
+        }
+
+        let mut backoff = 1;
         while running.load(Ordering::Acquire) {
             let updates = match self.get_updates().await {
                 Ok(updates) => updates,
                 Err(err) => {
-                    log::error!("[TelegramBot] failed to getUpdates, {}", err.to_string());
+                    log::error!(
+                        "[TelegramBot] failed to getUpdates, retry in {} seconds, {}",
+                        backoff,
+                        err
+                    );
+                    task::sleep(std::time::Duration::from_secs(backoff)).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
                     continue;
                 }
             };
+            backoff = 1;
 
             for update in &updates {
-                if update.message.is_none() {
-                    continue;
+                if let Err(err) = self.handler.handle_update(update).await {
+                    log::error!("[TelegramBot] failed to handle message, {}", err);
                 }
-                match self.handle_message(update.message.as_ref().unwrap()).await {
-                    Ok(_) => {}
-                    Err(_) => {}
-                };
             }
         }
 
@@ -191,6 +364,38 @@ impl Poller {
     }
 }
 
+/// Longest wait in seconds before retrying a failed `setWebhook` or `getUpdates`.
+const MAX_BACKOFF: u64 = 300;
+
+/// Registers the webhook, retrying with exponential backoff until it succeeds
+/// or the bot is stopped. No updates arrive until then.
+async fn set_webhook(ctx: &Context, running: &AtomicBool) {
+    let conf = &ctx.conf.telegram;
+    let data = SetWebhook {
+        url: conf.webhook_url.as_deref().unwrap(),
+        secret_token: conf.webhook_secret.as_deref().unwrap(),
+        allowed_updates: allowed_updates(),
+    };
+
+    let mut backoff = 1;
+    while running.load(Ordering::Acquire) {
+        match call_api::<_, bool>(ctx, "setWebhook", &data).await {
+            Ok(_) => {
+                log::info!("[TelegramBot] webhook set to {}", data.url);
+                return;
+            }
+            Err(err) => log::error!(
+                "[TelegramBot] failed to setWebhook, retry in {} seconds, {}",
+                backoff,
+                err
+            ),
+        }
+
+        task::sleep(std::time::Duration::from_secs(backoff)).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
 pub struct TelegramBot {
     ctx: Arc<Context>,
     state: Arc<AtomicBool>,
@@ -213,15 +418,81 @@ impl TelegramBot {
     pub fn start(&self) -> Result<()> {
         let ctx = self.ctx.clone();
         let state = self.state.clone();
-        if let Err(_) = state.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed) {
+        if state
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
             return Err(anyhow!("already running"));
         }
 
-        task::spawn(async move {
-            let mut poller = Poller::new(ctx);
-            poller.start_polling(state.as_ref()).await;
-        });
+        match ctx.conf.telegram.mode {
+            TelegramMode::Polling => {
+                let mut poller = Poller::new(ctx)?;
+                task::spawn(async move {
+                    poller.start_polling(state.as_ref()).await;
+                });
+            }
+            TelegramMode::Webhook => {
+                let conf = &ctx.conf.telegram;
+                if conf.webhook_url.is_none() || conf.webhook_secret.is_none() {
+                    return Err(anyhow!(
+                        "webhook mode requires telegram.webhook_url and telegram.webhook_secret"
+                    ));
+                }
+
+                task::spawn(async move {
+                    set_webhook(&ctx, state.as_ref()).await;
+                });
+            }
+        }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_start_command() {
+        assert_eq!(parse_start_command("/start abc"), Some((None, "abc")));
+        assert_eq!(parse_start_command("  /start   abc  "), Some((None, "abc")));
+        assert_eq!(
+            parse_start_command("/start@sps_bot abc"),
+            Some((Some("sps_bot"), "abc"))
+        );
+        assert_eq!(
+            parse_start_command("/start@other_bot abc"),
+            Some((Some("other_bot"), "abc"))
+        );
+    }
+
+    #[test]
+    fn ignores_start_command_without_argument() {
+        assert_eq!(parse_start_command("/start"), None);
+        assert_eq!(parse_start_command("/start@sps_bot"), None);
+        assert_eq!(parse_start_command("/start "), None);
+        assert_eq!(parse_start_command("/stop abc"), None);
+        assert_eq!(parse_start_command("/startx abc"), None);
+    }
+
+    #[test]
+    fn deserializes_supergroup_and_channel_ids() {
+        let update: Update = serde_json::from_str(
+            r#"{"update_id":1,"message":{"message_id":2,"chat":{"id":-1001234567890,"type":"supergroup"},"text":"/start@sps_bot abc"}}"#,
+        )
+        .unwrap();
+        let message = update.message.unwrap();
+        assert_eq!(message.chat.id, -1001234567890);
+        assert_eq!(message.chat.chat_type, "supergroup");
+
+        let update: Update = serde_json::from_str(
+            r#"{"update_id":3,"channel_post":{"message_id":4,"chat":{"id":-1009876543210,"type":"channel","username":"news"}}}"#,
+        )
+        .unwrap();
+        let post = update.channel_post.unwrap();
+        assert_eq!(post.chat.id, -1009876543210);
+        assert_eq!(post.chat.username.as_deref(), Some("news"));
+    }
+}
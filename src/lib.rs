@@ -3,6 +3,7 @@ pub mod handler;
 pub mod job;
 pub mod logic;
 pub mod model;
+pub mod queue;
 pub mod service;
 pub mod transport;
 pub mod types;
@@ -1,61 +1,226 @@
+use super::siwe::SiweMessage;
 use crate::service::Context;
 use crate::types::*;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use async_std::sync::Arc;
 use ethers_core::types::{Address, Signature};
+use ethers_core::utils::hex;
 use hmac::{Hmac, Mac};
 use jwt::SignWithKey;
-use sha2::Sha256;
-use std::collections::BTreeMap;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use redis::AsyncCommands;
+use sha2::{Digest, Sha256};
+use std::convert::TryFrom;
+use std::ops::DerefMut;
 use tide::{Body, Request, Response};
 
-fn verify_signature(address: &str, signature: &str, timestamp: i64) -> Result<()> {
+/// Tolerated clock difference between the wallet and the server in seconds
+const CLOCK_SKEW: i64 = 60;
+
+fn nonce_key(nonce: &str) -> String {
+    format!("sps_siwe_nonce:{}", nonce)
+}
+
+async fn verify_signature(
+    ctx: &Context,
+    message: &str,
+    address: &str,
+    signature: &str,
+) -> Result<()> {
     let address = address.parse::<Address>()?;
-    let signature = signature.parse::<Signature>()?;
+    let signature = hex::decode(signature.trim_start_matches("0x"))?;
+
+    // Plain EOAs are verified by ECDSA recovery
+    if let Ok(ecdsa) = Signature::try_from(signature.as_slice()) {
+        if ecdsa.verify(message, address).is_ok() {
+            return Ok(());
+        }
+    }
+
+    // Smart contract wallets validate the signature themselves
+    match &ctx.eip1271 {
+        None => Err(anyhow!("invalid signature")),
+        Some(verifier) => {
+            let hash = ethers_core::utils::hash_message(message);
+            verifier.verify(address, hash, &signature).await
+        }
+    }
+}
+
+/// Redis key of a refresh token, only the SHA-256 hash of the token is stored.
+fn refresh_token_key(refresh_token: &str) -> String {
+    let hash = Sha256::digest(refresh_token.as_bytes());
+    format!("sps_refresh_token:{}", hex::encode(hash))
+}
+
+/// Redis key that marks an access token as revoked until it expires.
+pub fn revoked_token_key(jti: &str) -> String {
+    format!("sps_revoked_token:{}", jti)
+}
+
+/// Issues a new access token and refresh token pair for the user.
+async fn issue_tokens(ctx: &Context, address: &str) -> Result<AuthResponse> {
+    let conf = &ctx.conf.server;
+    let now = chrono::Utc::now().timestamp();
+
+    let claims = Claims {
+        sub: address.to_string(),
+        iss: conf.token_issuer.clone(),
+        aud: conf.token_audience.clone(),
+        jti: uuid::Uuid::new_v4().to_string(),
+        iat: now,
+        exp: now + conf.access_expire,
+    };
+
+    let key: Hmac<Sha256> = Hmac::new_from_slice(conf.access_secret.as_bytes())?;
+    let access_token = claims.sign_with_key(&key)?;
+
+    let buf: Vec<u8> = thread_rng().sample_iter(&Alphanumeric).take(64).collect();
+    let refresh_token = String::from_utf8_lossy(buf.as_slice()).to_string();
+
+    let mut guard = ctx.redis_connection.lock().await?;
+    guard
+        .deref_mut()
+        .set_ex::<_, _, ()>(
+            refresh_token_key(&refresh_token),
+            address,
+            conf.refresh_expire as usize,
+        )
+        .await?;
+
+    Ok(AuthResponse {
+        access_token,
+        expires_in: conf.access_expire,
+        refresh_token,
+    })
+}
+
+/// Removes a refresh token and returns the address it was issued for.
+async fn take_refresh_token(ctx: &Context, refresh_token: &str) -> Result<Option<String>> {
+    let key = refresh_token_key(refresh_token);
+    let mut guard = ctx.redis_connection.lock().await?;
+    let (address, _): (Option<String>, i64) = redis::pipe()
+        .atomic()
+        .get(&key)
+        .del(&key)
+        .query_async(guard.deref_mut())
+        .await?;
 
-    let message = format!(
-        "I agree to connect my wallet to the simple push service. Timestamp: {}",
-        timestamp
-    );
+    Ok(address)
+}
+
+fn make_unauthorized_error(err: anyhow::Error) -> tide::Error {
+    tide::Error::new(401, anyhow!("Unauthorized: {}", err))
+}
 
-    signature.verify(message, address)?;
+/// Issues a single-use nonce for a Sign-In with Ethereum message.
+pub async fn get_nonce(req: Request<Arc<Context>>) -> tide::Result {
+    let buf: Vec<u8> = thread_rng().sample_iter(&Alphanumeric).take(17).collect();
+    let nonce = String::from_utf8_lossy(buf.as_slice()).to_string();
 
-    Ok(())
+    let nonce_expire = req.state().conf.auth.nonce_expire;
+    let mut guard = req.state().redis_connection.lock().await?;
+    guard
+        .deref_mut()
+        .set_ex::<_, _, ()>(nonce_key(&nonce), 1, nonce_expire)
+        .await?;
+
+    let res = NonceResponse { nonce };
+
+    Ok(Response::builder(200).body(Body::from_json(&res)?).build())
 }
 
 pub async fn auth(mut req: Request<Arc<Context>>) -> tide::Result {
     let data: AuthRequest = req.body_json().await?;
 
+    let message = SiweMessage::parse(&data.message).map_err(make_unauthorized_error)?;
+
+    // Consume nonce before anything else, so that a made up or replayed nonce
+    // never reaches the RPC endpoint. DEL both checks and consumes it atomically.
+    let consumed: i64 = {
+        let mut guard = req.state().redis_connection.lock().await?;
+        guard.deref_mut().del(nonce_key(&message.nonce)).await?
+    };
+    if consumed == 0 {
+        return Err(make_unauthorized_error(anyhow!("invalid nonce")));
+    }
+
+    // Validate Sign-In with Ethereum message
+    let conf = &req.state().conf.auth;
+    message
+        .validate(
+            &conf.domain,
+            &conf.uri,
+            conf.chain_id,
+            chrono::Utc::now(),
+            chrono::Duration::seconds(CLOCK_SKEW),
+        )
+        .map_err(make_unauthorized_error)?;
+
     // Verify wallet signature
-    verify_signature(&data.address, &data.signature, data.timestamp)?;
+    verify_signature(
+        req.state(),
+        &data.message,
+        &message.address,
+        &data.signature,
+    )
+    .await
+    .map_err(make_unauthorized_error)?;
+
+    let address = message.address;
 
     // Ensure that user record exist
     let user_model = &req.state().user_model;
-    let result = user_model.find_one_by_wallet_address(&data.address).await;
+    let result = user_model.find_one_by_wallet_address(&address).await;
     if let Err(err) = result {
         if !crate::model::is_not_found_record_err(&err) {
             return Err(err.into());
         }
 
-        let user = crate::model::User::new(&data.address);
-        user_model.insert(&user).await?;
+        let user = crate::model::User::new(&address);
+        crate::model::insert_user(&req.state().pool, &user).await?;
     }
 
-    // Generate JWT access token for user
-    let now = chrono::Utc::now().timestamp();
-    let access_expire = req.state().conf.server.access_expire;
+    let res = issue_tokens(req.state(), &address).await?;
+
+    Ok(Response::builder(200).body(Body::from_json(&res)?).build())
+}
 
-    let mut claims = BTreeMap::new();
-    claims.insert("iat", now.to_string());
-    claims.insert("exp", (now + access_expire).to_string());
-    claims.insert("username", data.address);
+/// Exchanges a refresh token for a new token pair, the refresh token can only be
+/// used once.
+pub async fn refresh_token(mut req: Request<Arc<Context>>) -> tide::Result {
+    let data: RefreshTokenRequest = req.body_json().await?;
 
-    let buf = req.state().conf.server.access_secret.as_bytes();
-    let key: Hmac<Sha256> = Hmac::new_from_slice(buf).unwrap();
+    let address = take_refresh_token(req.state(), &data.refresh_token).await?;
+    let address =
+        address.ok_or_else(|| make_unauthorized_error(anyhow!("invalid refresh token")))?;
 
-    let res = AuthResponse {
-        access_token: claims.sign_with_key(&key)?,
-    };
+    let res = issue_tokens(req.state(), &address).await?;
 
     Ok(Response::builder(200).body(Body::from_json(&res)?).build())
 }
+
+/// Revokes the access token of the request and, if given, the refresh token.
+pub async fn logout(mut req: Request<Arc<Context>>) -> tide::Result {
+    let data: LogoutRequest = super::body_json_or_default(&mut req).await?;
+    let claims = req.ext::<Claims>().unwrap().clone();
+
+    if let Some(refresh_token) = &data.refresh_token {
+        take_refresh_token(req.state(), refresh_token).await?;
+    }
+
+    // Keep the revocation until the access token would have expired anyway
+    let ttl = claims.exp - chrono::Utc::now().timestamp();
+    if ttl > 0 {
+        let mut guard = req.state().redis_connection.lock().await?;
+        guard
+            .deref_mut()
+            .set_ex::<_, _, ()>(revoked_token_key(&claims.jti), 1, ttl as usize)
+            .await?;
+    }
+
+    Ok(Response::builder(200)
+        .body(Body::from_json(&serde_json::json!({}))?)
+        .build())
+}
@@ -0,0 +1,270 @@
+use anyhow::{anyhow, Result};
+use ethers_core::abi::{self, Token};
+use ethers_core::types::{Address, Bytes, H256, U64};
+use ethers_core::utils::hex;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// `bytes4(keccak256("isValidSignature(bytes32,bytes)"))`, returned by the
+/// contract when the signature is valid.
+const MAGIC_VALUE: [u8; 4] = [0x16, 0x26, 0xba, 0x7e];
+
+#[derive(Serialize)]
+struct JsonRpcRequest<'a> {
+    jsonrpc: &'a str,
+    id: u64,
+    method: &'a str,
+    params: Value,
+}
+
+#[derive(Deserialize)]
+struct JsonRpcError {
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct JsonRpcResponse<T> {
+    result: Option<T>,
+    error: Option<JsonRpcError>,
+}
+
+impl<T> JsonRpcResponse<T> {
+    fn into_result(self) -> Result<T> {
+        match self {
+            JsonRpcResponse {
+                error: Some(error), ..
+            } => Err(anyhow!(error.message)),
+            JsonRpcResponse {
+                result: Some(result),
+                ..
+            } => Ok(result),
+            _ => Err(anyhow!("invalid result")),
+        }
+    }
+}
+
+async fn call<T: serde::de::DeserializeOwned>(
+    rpc_url: &str,
+    method: &str,
+    params: Value,
+) -> Result<T> {
+    let data = &JsonRpcRequest {
+        jsonrpc: "2.0",
+        id: 1,
+        method,
+        params,
+    };
+
+    let mut res = match surf::post(rpc_url).body_json(data) {
+        Ok(req) => match req.await {
+            Ok(res) => res,
+            Err(err) => return Err(err.into_inner()),
+        },
+        Err(err) => return Err(err.into_inner()),
+    };
+
+    match res.body_json::<JsonRpcResponse<T>>().await {
+        Ok(res) => res.into_result(),
+        Err(err) => Err(err.into_inner()),
+    }
+}
+
+/// Calldata of `isValidSignature(hash, signature)`.
+fn encode_call(hash: H256, signature: &[u8]) -> Vec<u8> {
+    let mut data = MAGIC_VALUE.to_vec();
+    data.extend(abi::encode(&[
+        Token::FixedBytes(hash.as_bytes().to_vec()),
+        Token::Bytes(signature.to_vec()),
+    ]));
+    data
+}
+
+/// Checks the value returned by `isValidSignature`.
+fn check_result(result: &[u8]) -> Result<()> {
+    if !result.starts_with(&MAGIC_VALUE) {
+        return Err(anyhow!("signature rejected by contract"));
+    }
+
+    Ok(())
+}
+
+fn check_chain_id(rpc_chain_id: u64, chain_id: u64) -> Result<()> {
+    if rpc_chain_id != chain_id {
+        return Err(anyhow!(
+            "rpc endpoint serves chain {}, expected {}",
+            rpc_chain_id,
+            chain_id
+        ));
+    }
+
+    Ok(())
+}
+
+/// Verifies signatures of smart contract wallets, as specified by EIP-1271.
+pub struct Verifier {
+    rpc_url: String,
+    chain_id: u64,
+    /// Chain id reported by the endpoint, zero until it was fetched
+    rpc_chain_id: AtomicU64,
+}
+
+impl Verifier {
+    pub fn new(rpc_url: &str, chain_id: u64) -> Self {
+        Verifier {
+            rpc_url: rpc_url.to_string(),
+            chain_id,
+            rpc_chain_id: AtomicU64::new(0),
+        }
+    }
+
+    async fn rpc_chain_id(&self) -> Result<u64> {
+        let cached = self.rpc_chain_id.load(Ordering::Relaxed);
+        if cached != 0 {
+            return Ok(cached);
+        }
+
+        let rpc_chain_id: U64 = call(&self.rpc_url, "eth_chainId", json!([])).await?;
+        self.rpc_chain_id
+            .store(rpc_chain_id.as_u64(), Ordering::Relaxed);
+        Ok(rpc_chain_id.as_u64())
+    }
+
+    /// Asks the smart contract wallet at `address` whether `signature` is valid
+    /// for `hash`.
+    pub async fn verify(&self, address: Address, hash: H256, signature: &[u8]) -> Result<()> {
+        check_chain_id(self.rpc_chain_id().await?, self.chain_id)?;
+
+        let params = json!([
+            {
+                "to": format!("{:?}", address),
+                "data": format!("0x{}", hex::encode(encode_call(hash, signature))),
+            },
+            "latest"
+        ]);
+        let result: Bytes = call(&self.rpc_url, "eth_call", params).await?;
+        check_result(result.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_response(body: &str) -> Result<Bytes> {
+        serde_json::from_str::<JsonRpcResponse<Bytes>>(body)?.into_result()
+    }
+
+    #[test]
+    fn encodes_is_valid_signature_call() {
+        let hash = H256::repeat_byte(0xab);
+        let data = encode_call(hash, &[0x01, 0x02, 0x03]);
+
+        let expected = [
+            "1626ba7e",
+            "abababababababababababababababababababababababababababababababab",
+            // Offset of the signature bytes
+            "0000000000000000000000000000000000000000000000000000000000000040",
+            "0000000000000000000000000000000000000000000000000000000000000003",
+            "0102030000000000000000000000000000000000000000000000000000000000",
+        ]
+        .concat();
+        assert_eq!(hex::encode(data), expected);
+    }
+
+    #[test]
+    fn accepts_magic_value() {
+        let result = parse_response(
+            r#"{"jsonrpc":"2.0","id":1,"result":"0x1626ba7e00000000000000000000000000000000000000000000000000000000"}"#,
+        )
+        .unwrap();
+        assert!(check_result(result.as_ref()).is_ok());
+    }
+
+    #[test]
+    fn rejects_wrong_magic_value() {
+        let result = parse_response(
+            r#"{"jsonrpc":"2.0","id":1,"result":"0xffffffff00000000000000000000000000000000000000000000000000000000"}"#,
+        )
+        .unwrap();
+        assert!(check_result(result.as_ref()).is_err());
+
+        // Calls to accounts without code return nothing
+        let result = parse_response(r#"{"jsonrpc":"2.0","id":1,"result":"0x"}"#).unwrap();
+        assert!(check_result(result.as_ref()).is_err());
+    }
+
+    #[test]
+    fn rejects_revert() {
+        let err = parse_response(
+            r#"{"jsonrpc":"2.0","id":1,"error":{"code":3,"message":"execution reverted","data":"0x"}}"#,
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "execution reverted");
+    }
+
+    #[test]
+    fn rejects_chain_id_mismatch() {
+        assert!(check_chain_id(1, 1).is_ok());
+        assert_eq!(
+            check_chain_id(5, 1).unwrap_err().to_string(),
+            "rpc endpoint serves chain 5, expected 1"
+        );
+    }
+
+    #[async_std::test]
+    async fn uses_cached_chain_id() {
+        // Nothing listens on the endpoint, only the cached value can be used
+        let verifier = Verifier::new("http://127.0.0.1:9", 1);
+        verifier.rpc_chain_id.store(1, Ordering::Relaxed);
+        assert_eq!(verifier.rpc_chain_id().await.unwrap(), 1);
+    }
+
+    /// Contract whose `isValidSignature` accepts everything: the runtime code
+    /// returns the magic value for any call.
+    const ACCEPT_ALL_INIT_CODE: &str = concat!(
+        // CODECOPY the 41 byte runtime code at offset 12 and RETURN it
+        "6029600c60003960296000f3",
+        // PUSH32 magic value, MSTORE at 0, RETURN 32 bytes
+        "7f1626ba7e00000000000000000000000000000000000000000000000000000000",
+        "60005260206000f3",
+    );
+
+    async fn deploy(rpc_url: &str, init_code: &str) -> Address {
+        let accounts: Vec<Address> = call(rpc_url, "eth_accounts", json!([])).await.unwrap();
+        let params = json!([{
+            "from": format!("{:?}", accounts[0]),
+            "data": format!("0x{}", init_code),
+        }]);
+        let tx: H256 = call(rpc_url, "eth_sendTransaction", params).await.unwrap();
+
+        let receipt: Value = call(rpc_url, "eth_getTransactionReceipt", json!([tx]))
+            .await
+            .unwrap();
+        serde_json::from_value(receipt["contractAddress"].clone()).unwrap()
+    }
+
+    /// Runs against a local dev node started with `anvil`, override the
+    /// endpoint with `ANVIL_URL`.
+    #[async_std::test]
+    #[ignore]
+    async fn verifies_against_anvil() {
+        let rpc_url =
+            std::env::var("ANVIL_URL").unwrap_or_else(|_| "http://127.0.0.1:8545".to_string());
+        let hash = ethers_core::utils::hash_message("hello");
+
+        let wallet = deploy(&rpc_url, ACCEPT_ALL_INIT_CODE).await;
+        let verifier = Verifier::new(&rpc_url, 31337);
+        verifier.verify(wallet, hash, &[0x01]).await.unwrap();
+
+        // An account without code can't validate signatures
+        let err = verifier
+            .verify(Address::repeat_byte(0x11), hash, &[0x01])
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "signature rejected by contract");
+
+        let verifier = Verifier::new(&rpc_url, 1);
+        assert!(verifier.verify(wallet, hash, &[0x01]).await.is_err());
+    }
+}
@@ -0,0 +1,202 @@
+use crate::model::{self, transport_type};
+use crate::service::Context;
+use crate::transport::{self, Transport as _};
+use crate::types::*;
+use anyhow::anyhow;
+use async_std::sync::Arc;
+use rand::{thread_rng, Rng};
+use redis::AsyncCommands;
+use std::ops::DerefMut;
+use tide::{Body, Request, Response};
+
+const MAX_VERIFY_ATTEMPTS: i64 = 5;
+
+fn verify_code_key(user_id: i64) -> String {
+    format!("sps_email_verify:{}", user_id)
+}
+
+fn verify_attempts_key(user_id: i64) -> String {
+    format!("sps_email_verify_attempts:{}", user_id)
+}
+
+fn bind_user_key(user_id: i64) -> String {
+    format!("sps_email_bind:user:{}", user_id)
+}
+
+fn bind_address_key(address: &str) -> String {
+    format!("sps_email_bind:address:{}", address.to_lowercase())
+}
+
+/// Sets `key` for `interval` seconds unless it is already set. Returns `false`
+/// if the key is still held.
+async fn acquire_cooldown(
+    req: &Request<Arc<Context>>,
+    key: &str,
+    interval: usize,
+) -> tide::Result<bool> {
+    let mut guard = req.state().redis_connection.lock().await?;
+    let acquired = redis::cmd("SET")
+        .arg(key)
+        .arg(1)
+        .arg("NX")
+        .arg("EX")
+        .arg(interval)
+        .query_async::<_, Option<String>>(guard.deref_mut())
+        .await?
+        .is_some();
+    Ok(acquired)
+}
+
+pub async fn bind_email(mut req: Request<Arc<Context>>) -> tide::Result {
+    let data: BindEmailRequest = req.body_json().await?;
+    let conf = req
+        .state()
+        .conf
+        .email
+        .as_ref()
+        .ok_or_else(|| tide::Error::new(400, anyhow!("Email transport is not enabled")))?;
+    let address = data
+        .address
+        .parse::<lettre::Address>()
+        .map_err(|_| tide::Error::new(400, anyhow!("Invalid email address")))?
+        .to_string();
+
+    let wallet_address = req.ext::<String>().unwrap();
+    let user = req
+        .state()
+        .user_model
+        .find_one_by_wallet_address(wallet_address)
+        .await?;
+
+    // Throttle verification emails per user and per recipient
+    if !acquire_cooldown(&req, &bind_user_key(user.id), conf.bind_interval).await?
+        || !acquire_cooldown(&req, &bind_address_key(&address), conf.bind_interval).await?
+    {
+        return Err(tide::Error::new(
+            429,
+            anyhow!("Verification email was sent recently, try again later"),
+        ));
+    }
+
+    // Save the address as an unconnected transport until it is verified
+    let transport_model = &req.state().transport_model;
+    let result = transport_model
+        .find_one_by_user_id_type(user.id, transport_type::EMAIL)
+        .await;
+    match result {
+        Ok(_) => {
+            transport_model
+                .update_chat_id(user.id, None, transport_type::EMAIL, &address)
+                .await?;
+            transport_model
+                .update_connected(user.id, transport_type::EMAIL, false)
+                .await?;
+        }
+        Err(err) => {
+            if !model::is_not_found_record_err(&err) {
+                return Err(err.into());
+            }
+
+            let mut transport = model::Transport::new(user.id, transport_type::EMAIL);
+            transport.chat_id = Some(address.clone());
+            transport_model.insert(&transport).await?;
+        }
+    }
+
+    // Issue a new verification code, failed attempts keep counting against it
+    let code = format!("{:06}", thread_rng().gen_range(0..1_000_000));
+    {
+        let mut guard = req.state().redis_connection.lock().await?;
+        guard
+            .deref_mut()
+            .set_ex::<_, _, ()>(verify_code_key(user.id), &code, conf.verify_code_expire)
+            .await?;
+    }
+
+    let content = format!(
+        "Your verification code is {}. It expires in {} minutes.",
+        code,
+        conf.verify_code_expire / 60
+    );
+    transport::Email::new(conf)?
+        .push(&address, "Verify your email address", &content)
+        .await?;
+
+    let res = Transport {
+        transport_type: transport_type::EMAIL.to_string(),
+        chat_id: Some(address),
+        connected: false,
+    };
+
+    Ok(Response::builder(200).body(Body::from_json(&res)?).build())
+}
+
+pub async fn verify_email(mut req: Request<Arc<Context>>) -> tide::Result {
+    let data: VerifyEmailRequest = req.body_json().await?;
+    let conf = req
+        .state()
+        .conf
+        .email
+        .as_ref()
+        .ok_or_else(|| tide::Error::new(400, anyhow!("Email transport is not enabled")))?;
+
+    let wallet_address = req.ext::<String>().unwrap();
+    let user = req
+        .state()
+        .user_model
+        .find_one_by_wallet_address(wallet_address)
+        .await?;
+
+    let code_key = verify_code_key(user.id);
+    let attempts_key = verify_attempts_key(user.id);
+    {
+        let mut guard = req.state().redis_connection.lock().await?;
+        let attempts: Option<i64> = guard.deref_mut().get(&attempts_key).await?;
+        if attempts.unwrap_or(0) >= MAX_VERIFY_ATTEMPTS {
+            return Err(tide::Error::new(
+                429,
+                anyhow!("Too many invalid verification codes, try again later"),
+            ));
+        }
+
+        let code: Option<String> = guard.deref_mut().get(&code_key).await?;
+        let code =
+            code.ok_or_else(|| tide::Error::new(400, anyhow!("Verification code has expired")))?;
+
+        if code != data.code {
+            let attempts: i64 = guard.deref_mut().incr(&attempts_key, 1).await?;
+            guard
+                .deref_mut()
+                .expire::<_, ()>(&attempts_key, conf.verify_code_expire)
+                .await?;
+            // The counter outlives the code, so that binding again does not
+            // grant more guesses
+            if attempts >= MAX_VERIFY_ATTEMPTS {
+                guard.deref_mut().del::<_, ()>(&code_key).await?;
+            }
+
+            return Err(tide::Error::new(400, anyhow!("Invalid verification code")));
+        }
+
+        guard
+            .deref_mut()
+            .del::<_, ()>(&[&code_key, &attempts_key])
+            .await?;
+    }
+
+    let transport_model = &req.state().transport_model;
+    transport_model
+        .update_connected(user.id, transport_type::EMAIL, true)
+        .await?;
+    let transport = transport_model
+        .find_one_by_user_id_type(user.id, transport_type::EMAIL)
+        .await?;
+
+    let res = Transport {
+        transport_type: transport.transport_type,
+        chat_id: transport.chat_id,
+        connected: transport.connected,
+    };
+
+    Ok(Response::builder(200).body(Body::from_json(&res)?).build())
+}
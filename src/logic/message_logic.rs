@@ -0,0 +1,143 @@
+use crate::model;
+use crate::service::Context;
+use crate::types::*;
+use anyhow::anyhow;
+use async_std::sync::Arc;
+use tide::{Body, Request, Response};
+
+const DEFAULT_PAGE_SIZE: i64 = 20;
+const MAX_PAGE_SIZE: i64 = 100;
+
+fn make_not_found_error() -> tide::Error {
+    tide::Error::new(404, anyhow!("Message not found"))
+}
+
+pub async fn get_message(req: Request<Arc<Context>>) -> tide::Result {
+    let message_id = req
+        .param("message_id")?
+        .parse::<i64>()
+        .map_err(|_| make_not_found_error())?;
+
+    let address = req.ext::<String>().unwrap();
+    let user = req
+        .state()
+        .user_model
+        .find_one_by_wallet_address(address)
+        .await?;
+
+    let message = match req.state().message_model.find_one_by_id(message_id).await {
+        Ok(message) => message,
+        Err(err) => {
+            return match model::is_not_found_record_err(&err) {
+                true => Err(make_not_found_error()),
+                false => Err(err.into()),
+            }
+        }
+    };
+    if message.user_id != user.id {
+        return Err(make_not_found_error());
+    }
+
+    let tasks = req
+        .state()
+        .task_model
+        .find_all_by_message_id(message.id)
+        .await?;
+
+    let res = GetMessageResponse {
+        id: message.id,
+        title: message.title,
+        content: message.content,
+        format: message.format,
+        send_time: message.send_time,
+        creation_time: message.creation_time,
+        tasks: tasks
+            .into_iter()
+            .map(|task| TaskStatus {
+                id: task.id,
+                transport_type: task.transport_type,
+                state: task.state,
+                retry_count: task.retry_count,
+                reason: task.reason,
+                last_attempt_time: task.last_attempt_time,
+                delivered_time: task.delivered_time,
+            })
+            .collect(),
+    };
+
+    Ok(Response::builder(200).body(Body::from_json(&res)?).build())
+}
+
+pub async fn list_messages(req: Request<Arc<Context>>) -> tide::Result {
+    let data = req.query::<ListMessagesRequest>()?;
+    let limit = data.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+        return Err(tide::Error::new(400, anyhow!("Invalid limit")));
+    }
+
+    let parse_time = |timestamp: &Option<Timestamp>, name: &str| match timestamp {
+        None => Ok(None),
+        Some(timestamp) => timestamp
+            .to_datetime()
+            .map(Some)
+            .ok_or_else(|| tide::Error::new(400, anyhow!("Invalid {}", name))),
+    };
+
+    let task_state = data.state.filter(|state| !state.is_empty());
+    if let Some(state) = &task_state {
+        let states = [
+            model::state::PENDING,
+            model::state::RETRYING,
+            model::state::FAIL,
+            model::state::DONE,
+        ];
+        if !states.contains(&state.as_str()) {
+            return Err(tide::Error::new(400, anyhow!("Invalid state")));
+        }
+    }
+
+    let filter = model::MessageFilter {
+        before_id: data.cursor,
+        since: parse_time(&data.from, "from")?,
+        until: parse_time(&data.to, "to")?,
+        task_state,
+        search: data.q.filter(|q| !q.trim().is_empty()),
+    };
+
+    let address = req.ext::<String>().unwrap();
+    let user = req
+        .state()
+        .user_model
+        .find_one_by_wallet_address(address)
+        .await?;
+
+    // Load one more row to tell whether there is a next page
+    let mut messages = req
+        .state()
+        .message_model
+        .find_page_by_user_id(user.id, &filter, limit + 1)
+        .await?;
+    let next_cursor = match messages.len() as i64 > limit {
+        false => None,
+        true => {
+            messages.truncate(limit as usize);
+            messages.last().map(|message| message.id)
+        }
+    };
+
+    let res = ListMessagesResponse {
+        messages: messages
+            .into_iter()
+            .map(|message| MessageItem {
+                id: message.id,
+                title: message.title,
+                content: message.content,
+                send_time: message.send_time,
+                creation_time: message.creation_time,
+            })
+            .collect(),
+        next_cursor,
+    };
+
+    Ok(Response::builder(200).body(Body::from_json(&res)?).build())
+}
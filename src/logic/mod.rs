@@ -1,7 +1,34 @@
 pub mod auth_logic;
+pub mod eip1271;
+pub mod email_logic;
 pub mod get_me_logic;
+pub mod message_logic;
+pub mod project_logic;
 pub mod push_message_logic;
+pub mod siwe;
+pub mod telegram_logic;
+pub mod webhook_logic;
 
 pub use auth_logic::*;
+pub use email_logic::*;
 pub use get_me_logic::*;
+pub use message_logic::*;
+pub use project_logic::*;
 pub use push_message_logic::*;
+pub use telegram_logic::*;
+pub use webhook_logic::*;
+
+/// Parses the JSON body of the request, an empty body yields the default value.
+pub(crate) async fn body_json_or_default<T, State>(
+    req: &mut tide::Request<State>,
+) -> tide::Result<T>
+where
+    T: serde::de::DeserializeOwned + Default,
+{
+    let body = req.body_bytes().await?;
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(T::default());
+    }
+
+    serde_json::from_slice(&body).map_err(|err| tide::Error::new(422, err))
+}
@@ -0,0 +1,223 @@
+use crate::model::{self, transport_type, Project};
+use crate::service::Context;
+use crate::types::*;
+use anyhow::anyhow;
+use async_std::sync::Arc;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use tide::{Body, Request, Response};
+
+const MAX_NAME_LENGTH: usize = 64;
+
+fn make_not_found_error() -> tide::Error {
+    tide::Error::new(404, anyhow!("Project not found"))
+}
+
+fn validate_project(name: &str, transports: &Option<Vec<String>>) -> tide::Result<()> {
+    let name_len = name.chars().count();
+    if name_len == 0 || name_len > MAX_NAME_LENGTH {
+        return Err(tide::Error::new(
+            400,
+            anyhow!("name must be 1 to {} characters", MAX_NAME_LENGTH),
+        ));
+    }
+
+    for transport in transports.iter().flatten() {
+        if !transport_type::ALL.contains(&transport.as_str()) {
+            return Err(tide::Error::new(
+                400,
+                anyhow!("Unknown transport type: {}", transport),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Loads the project in the `project` path parameter, if it belongs to the user.
+async fn find_project(req: &Request<Arc<Context>>) -> tide::Result<(model::User, Project)> {
+    let id = req
+        .param("project")?
+        .parse::<i64>()
+        .map_err(|_| make_not_found_error())?;
+
+    let address = req.ext::<String>().unwrap();
+    let user = req
+        .state()
+        .user_model
+        .find_one_by_wallet_address(address)
+        .await?;
+
+    let project = match req.state().project_model.find_one_by_id(id).await {
+        Ok(project) => project,
+        Err(err) => {
+            if model::is_not_found_record_err(&err) {
+                return Err(make_not_found_error());
+            }
+            return Err(err.into());
+        }
+    };
+    if project.user_id != user.id {
+        return Err(make_not_found_error());
+    }
+
+    Ok((user, project))
+}
+
+async fn make_project_item(ctx: &Context, project: Project) -> tide::Result<ProjectItem> {
+    let keys = ctx
+        .push_key_model
+        .find_all_valid_by_project(project.id)
+        .await?;
+
+    let project_ids = keys
+        .into_iter()
+        .map(|key| ProjectIdItem {
+            project_id: key.key,
+            current: key.expire_time.is_none(),
+            creation_time: key.creation_time,
+            last_used_time: key.last_used_time,
+            expire_time: key.expire_time,
+        })
+        .collect();
+
+    Ok(ProjectItem {
+        id: project.id,
+        name: project.name,
+        transports: project.transport_types,
+        project_ids,
+        signed: project.signing_secret.is_some(),
+        creation_time: project.creation_time,
+    })
+}
+
+pub async fn list_projects(req: Request<Arc<Context>>) -> tide::Result {
+    let address = req.ext::<String>().unwrap();
+    let user = req
+        .state()
+        .user_model
+        .find_one_by_wallet_address(address)
+        .await?;
+
+    let projects = req
+        .state()
+        .project_model
+        .find_all_by_user_id(user.id)
+        .await?;
+
+    let mut res = ListProjectsResponse {
+        projects: Vec::new(),
+    };
+    for project in projects {
+        res.projects
+            .push(make_project_item(req.state(), project).await?);
+    }
+
+    Ok(Response::builder(200).body(Body::from_json(&res)?).build())
+}
+
+pub async fn create_project(mut req: Request<Arc<Context>>) -> tide::Result {
+    let data: SaveProjectRequest = req.body_json().await?;
+    validate_project(&data.name, &data.transports)?;
+
+    let address = req.ext::<String>().unwrap();
+    let user = req
+        .state()
+        .user_model
+        .find_one_by_wallet_address(address)
+        .await?;
+
+    let mut project = Project::new(user.id, &data.name, data.transports);
+    project.id =
+        model::insert_project(&req.state().pool, &project, &model::gen_project_id()).await?;
+
+    let res = make_project_item(req.state(), project).await?;
+
+    Ok(Response::builder(200).body(Body::from_json(&res)?).build())
+}
+
+pub async fn update_project(mut req: Request<Arc<Context>>) -> tide::Result {
+    let data: SaveProjectRequest = req.body_json().await?;
+    validate_project(&data.name, &data.transports)?;
+
+    let (_, mut project) = find_project(&req).await?;
+    project.name = data.name;
+    project.transport_types = data.transports;
+    req.state().project_model.update(&project).await?;
+
+    let res = make_project_item(req.state(), project).await?;
+
+    Ok(Response::builder(200).body(Body::from_json(&res)?).build())
+}
+
+pub async fn delete_project(req: Request<Arc<Context>>) -> tide::Result {
+    let (user, project) = find_project(&req).await?;
+
+    // The default project holds the push key of the user
+    let default_project = req
+        .state()
+        .project_model
+        .find_one_by_project_id(&user.project_id)
+        .await?;
+    if default_project.id == project.id {
+        return Err(tide::Error::new(
+            400,
+            anyhow!("The default project cannot be deleted"),
+        ));
+    }
+
+    model::delete_project(&req.state().pool, project.id).await?;
+
+    let res = make_project_item(req.state(), project).await?;
+
+    Ok(Response::builder(200).body(Body::from_json(&res)?).build())
+}
+
+/// Issues a new push key for the project, the replaced keys optionally stay valid
+/// for a grace period.
+pub async fn rotate_project_id(mut req: Request<Arc<Context>>) -> tide::Result {
+    let data: RotateProjectIdRequest = super::body_json_or_default(&mut req).await?;
+
+    let grace_period = data.grace_period.unwrap_or(0);
+    let max_grace_period = req.state().conf.push.max_key_grace_period;
+    if !(0..=max_grace_period).contains(&grace_period) {
+        return Err(tide::Error::new(
+            400,
+            anyhow!("grace_period must be between 0 and {}", max_grace_period),
+        ));
+    }
+
+    let (_, project) = find_project(&req).await?;
+    let project_id = model::gen_project_id();
+    model::rotate_project_id(&req.state().pool, &project, &project_id, grace_period).await?;
+
+    let res = make_project_item(req.state(), project).await?;
+
+    Ok(Response::builder(200).body(Body::from_json(&res)?).build())
+}
+
+/// Enables or disables signing of push requests for the project. Enabling it
+/// always issues a new secret.
+pub async fn set_project_signing(mut req: Request<Arc<Context>>) -> tide::Result {
+    let data: SetProjectSigningRequest = req.body_json().await?;
+    let (_, project) = find_project(&req).await?;
+
+    let secret = match data.enabled {
+        true => {
+            let buf: Vec<u8> = thread_rng().sample_iter(&Alphanumeric).take(64).collect();
+            Some(String::from_utf8_lossy(buf.as_slice()).to_string())
+        }
+        false => None,
+    };
+    req.state()
+        .project_model
+        .update_signing_secret(project.id, secret.as_deref())
+        .await?;
+
+    let res = SetProjectSigningResponse {
+        enabled: data.enabled,
+        secret,
+    };
+
+    Ok(Response::builder(200).body(Body::from_json(&res)?).build())
+}
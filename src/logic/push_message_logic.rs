@@ -1,56 +1,500 @@
 use crate::model;
 use crate::service::Context;
-use crate::types::{PushMessageRequest, PushMessageResponse};
+use crate::types::{PushBatchItem, PushBatchResponse, PushMessageRequest, PushMessageResponse};
 use anyhow::anyhow;
 use async_std::sync::Arc;
-use redis::AsyncCommands;
+use chrono::{DateTime, Utc};
+use ethers_core::utils::hex;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
 use std::ops::DerefMut;
 use tide::{Body, Request, Response};
 
+/// Length of the `title` column in characters.
+const MAX_TITLE_LENGTH: usize = 64;
+
+/// Checks the fields against the constraints of the `message` table, so that a
+/// bad message is rejected before it reaches the database.
+fn validate_message(data: &PushMessageRequest) -> tide::Result<()> {
+    if data.title.chars().count() > MAX_TITLE_LENGTH {
+        return Err(tide::Error::new(
+            400,
+            anyhow!("Title must be at most {} characters", MAX_TITLE_LENGTH),
+        ));
+    }
+    // Postgres text cannot hold NUL characters
+    if data.title.contains('\0') || data.content.contains('\0') {
+        return Err(tide::Error::new(
+            400,
+            anyhow!("Message must not contain NUL characters"),
+        ));
+    }
+
+    validate_format(data)
+}
+
+fn validate_format(data: &PushMessageRequest) -> tide::Result<()> {
+    if let Some(format) = &data.format {
+        if !model::message_format::ALL.contains(&format.as_str()) {
+            return Err(tide::Error::new(400, anyhow!("Unknown format: {}", format)));
+        }
+    }
+    if let Some(overflow) = &data.overflow {
+        if !model::message_overflow::ALL.contains(&overflow.as_str()) {
+            return Err(tide::Error::new(
+                400,
+                anyhow!("Unknown overflow: {}", overflow),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Resolves when the message should be sent from `send_at` or `delay`.
+fn resolve_send_time(data: &PushMessageRequest, max_delay: i64) -> tide::Result<DateTime<Utc>> {
+    let now = Utc::now();
+    let send_time = match (&data.send_at, data.delay) {
+        (None, None) => return Ok(now),
+        (Some(_), Some(_)) => {
+            return Err(tide::Error::new(
+                400,
+                anyhow!("send_at and delay are mutually exclusive"),
+            ))
+        }
+        (Some(send_at), None) => send_at
+            .to_datetime()
+            .ok_or_else(|| tide::Error::new(400, anyhow!("Invalid send_at")))?,
+        (None, Some(delay)) => {
+            if delay < 0 {
+                return Err(tide::Error::new(400, anyhow!("Invalid delay")));
+            }
+            // Clamp to keep the duration in range, too long delays are rejected below
+            now + chrono::Duration::seconds(delay.min(max_delay + 1))
+        }
+    };
+
+    if send_time > now + chrono::Duration::seconds(max_delay) {
+        return Err(tide::Error::new(
+            400,
+            anyhow!(
+                "Message cannot be scheduled more than {} seconds ahead",
+                max_delay
+            ),
+        ));
+    }
+
+    Ok(std::cmp::max(send_time, now))
+}
+
+/// Reads the push key from the path, or from the `Authorization: Bearer` or
+/// `X-SPS-Key` header so that it stays out of URLs.
+pub fn push_key(req: &Request<Arc<Context>>) -> tide::Result<String> {
+    if let Ok(project_id) = req.param("project_id") {
+        return Ok(project_id.to_string());
+    }
+
+    if let Some(value) = req.header("X-SPS-Key") {
+        return Ok(value.last().as_str().to_string());
+    }
+
+    const PREFIX: &str = "Bearer ";
+    if let Some(values) = req.header("Authorization") {
+        for value in values {
+            if let Some(token) = value.as_str().strip_prefix(PREFIX) {
+                return Ok(token.to_string());
+            }
+        }
+    }
+
+    Err(tide::Error::new(401, anyhow!("Missing push key")))
+}
+
+fn make_signature_error(err: anyhow::Error) -> tide::Error {
+    tide::Error::new(401, anyhow!("Invalid signature: {}", err))
+}
+
+/// Verifies the `X-SPS-Signature` header, an HMAC-SHA256 of `{timestamp}.{body}`
+/// formatted as `sha256=<hex>`, and makes sure it is used only once.
+async fn verify_signature(
+    req: &Request<Arc<Context>>,
+    project: &model::Project,
+    secret: &str,
+    body: &[u8],
+) -> tide::Result<()> {
+    let timestamp = req
+        .header("X-SPS-Timestamp")
+        .and_then(|value| value.last().as_str().parse::<i64>().ok())
+        .ok_or_else(|| make_signature_error(anyhow!("missing timestamp")))?;
+    let signature = req
+        .header("X-SPS-Signature")
+        .and_then(|value| {
+            value
+                .last()
+                .as_str()
+                .strip_prefix("sha256=")
+                .map(String::from)
+        })
+        .ok_or_else(|| make_signature_error(anyhow!("missing signature")))?;
+
+    let window = req.state().conf.push.signature_window;
+    if (chrono::Utc::now().timestamp() - timestamp).abs() > window {
+        return Err(make_signature_error(anyhow!("stale timestamp")));
+    }
+
+    let signature = hex::decode(&signature).map_err(|err| make_signature_error(err.into()))?;
+    let mut mac: Hmac<Sha256> = Hmac::new_from_slice(secret.as_bytes())?;
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    mac.verify_slice(&signature)
+        .map_err(|_| make_signature_error(anyhow!("signature mismatch")))?;
+
+    // Remember the signature for as long as its timestamp is accepted
+    let nonce_key = format!(
+        "sps_push_signature:{}:{}",
+        project.id,
+        hex::encode(&signature)
+    );
+    let fresh: bool = {
+        let mut guard = req.state().redis_connection.lock().await?;
+        redis::cmd("SET")
+            .arg(nonce_key)
+            .arg(1)
+            .arg("NX")
+            .arg("EX")
+            .arg(2 * window)
+            .query_async::<_, Option<String>>(guard.deref_mut())
+            .await?
+            .is_some()
+    };
+    if !fresh {
+        return Err(make_signature_error(anyhow!("duplicate signature")));
+    }
+
+    Ok(())
+}
+
+const MAX_IDEMPOTENCY_KEY_LENGTH: usize = 255;
+
+/// Reads the idempotency key from the `Idempotency-Key` header or the request.
+fn idempotency_key(
+    req: &Request<Arc<Context>>,
+    data: &PushMessageRequest,
+) -> tide::Result<Option<String>> {
+    let header = req
+        .header("Idempotency-Key")
+        .map(|value| value.last().as_str().to_string());
+    let key = match (header.as_deref(), data.idempotency_key.as_deref()) {
+        (Some(header), Some(field)) if header != field => {
+            return Err(tide::Error::new(
+                400,
+                anyhow!("Idempotency-Key header and idempotency_key differ"),
+            ))
+        }
+        (Some(key), _) | (None, Some(key)) => key.to_string(),
+        (None, None) => return Ok(None),
+    };
+
+    validate_idempotency_key(&key)?;
+
+    Ok(Some(key))
+}
+
+fn validate_idempotency_key(key: &str) -> tide::Result<()> {
+    if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LENGTH {
+        return Err(tide::Error::new(
+            400,
+            anyhow!(
+                "Idempotency key must be 1 to {} bytes",
+                MAX_IDEMPOTENCY_KEY_LENGTH
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Returns the result of the push that created a message with the idempotency
+/// key, unless it is older than the retention window.
+async fn find_idempotent_result(
+    ctx: &Context,
+    user_id: i64,
+    idempotency_key: &str,
+) -> tide::Result<Option<PushMessageResponse>> {
+    let message = match ctx
+        .message_model
+        .find_one_by_idempotency_key(user_id, idempotency_key)
+        .await
+    {
+        Ok(message) => message,
+        Err(err) => {
+            if model::is_not_found_record_err(&err) {
+                return Ok(None);
+            }
+            return Err(err.into());
+        }
+    };
+
+    let retention = chrono::Duration::seconds(ctx.conf.push.idempotency_retention);
+    if message.creation_time + retention < Utc::now() {
+        ctx.message_model.clear_idempotency_key(message.id).await?;
+        return Ok(None);
+    }
+
+    let tasks = ctx.task_model.find_all_by_message_id(message.id).await?;
+
+    Ok(Some(PushMessageResponse {
+        status: "queued".to_string(),
+        message_id: message.id,
+        task_ids: tasks.iter().map(|task| task.id).collect(),
+    }))
+}
+
+fn make_replayed_response(res: &PushMessageResponse) -> tide::Result {
+    Ok(Response::builder(200)
+        .header("Idempotent-Replayed", "true")
+        .body(Body::from_json(res)?)
+        .build())
+}
+
+/// Finds the project of the push key and records that the key was used.
+async fn find_project(
+    req: &Request<Arc<Context>>,
+    project_id: &str,
+) -> tide::Result<model::Project> {
+    let project = req
+        .state()
+        .project_model
+        .find_one_by_project_id(project_id)
+        .await?;
+
+    if let Err(err) = req.state().push_key_model.touch(project_id).await {
+        log::error!("failed to record push key usage, {}", err);
+    }
+
+    Ok(project)
+}
+
+fn new_message(
+    project: &model::Project,
+    data: &PushMessageRequest,
+    send_time: DateTime<Utc>,
+    idempotency_key: Option<String>,
+) -> model::Message {
+    let mut message = model::Message::new(project.user_id, &data.title, &data.content);
+    message.project = Some(project.id);
+    if let Some(format) = &data.format {
+        message.format = format.clone();
+    }
+    if let Some(overflow) = &data.overflow {
+        message.overflow = overflow.clone();
+    }
+    message.send_time = send_time;
+    message.idempotency_key = idempotency_key;
+    message
+}
+
 pub async fn push_message(mut req: Request<Arc<Context>>) -> tide::Result {
+    let project_id = push_key(&req)?;
+    let project = find_project(&req, &project_id).await?;
+
     let data = match req.method() {
-        http_types::Method::Get => req.query::<PushMessageRequest>()?,
-        http_types::Method::Post => req.body_json::<PushMessageRequest>().await?,
+        http_types::Method::Get => {
+            if project.signing_secret.is_some() {
+                return Err(tide::Error::new(
+                    405,
+                    anyhow!("Signed pushes must use POST"),
+                ));
+            }
+            req.query::<PushMessageRequest>()?
+        }
+        http_types::Method::Post => {
+            let body = req.body_bytes().await?;
+            if let Some(secret) = &project.signing_secret {
+                verify_signature(&req, &project, secret, &body).await?;
+            }
+            serde_json::from_slice::<PushMessageRequest>(&body)
+                .map_err(|err| tide::Error::new(422, err))?
+        }
         _ => return Err(tide::Error::new(400, anyhow!("Bad request"))),
     };
-    let project_id = req.param("project_id").unwrap();
+    validate_message(&data)?;
+    let send_time = resolve_send_time(&data, req.state().conf.push.max_delay)?;
+
+    let idempotency_key = idempotency_key(&req, &data)?;
+    if let Some(key) = &idempotency_key {
+        if let Some(res) = find_idempotent_result(req.state(), project.user_id, key).await? {
+            return make_replayed_response(&res);
+        }
+    }
 
     // Save message & task
-    let user = req
-        .state()
-        .user_model
-        .find_one_by_project_id(project_id)
-        .await?;
     let transports = req
         .state()
         .transport_model
-        .find_all_by_user_id(user.id)
+        .find_all_connected_by_user_id(project.user_id)
         .await?;
 
-    let task_ids = model::insert_message(
-        &req.state().pool,
-        user.id,
-        &data.title,
-        &data.content,
-        &transports,
-    )
-    .await?;
-
-    // Adding to redis task queue
-    let ts = chrono::Utc::now().timestamp();
+    let message = new_message(&project, &data, send_time, idempotency_key);
+    let result = model::insert_message(&req.state().pool, &project, &message, &transports).await?;
+    let (message_id, task_ids) = match (result, &message.idempotency_key) {
+        (Some(result), _) => result,
+        // A concurrent request with the same key won the race
+        (None, Some(key)) => {
+            match find_idempotent_result(req.state(), project.user_id, key).await? {
+                Some(res) => return make_replayed_response(&res),
+                None => return Err(tide::Error::new(409, anyhow!("Idempotency key conflict"))),
+            }
+        }
+        (None, None) => return Err(tide::Error::new(500, anyhow!("Message was not saved"))),
+    };
+
+    // Adding to task queue
+    let ts = send_time.timestamp();
     let items = task_ids
-        .into_iter()
-        .map(|task_id| (ts, task_id))
+        .iter()
+        .map(|task_id| (*task_id, ts))
         .collect::<Vec<(i64, i64)>>();
-    let mut guard = req.state().redis_connection.lock().await;
-    guard
-        .deref_mut()
-        .zadd_multiple(req.state().conf.redis.queue_name.as_str(), items.as_slice())
-        .await?;
+    req.state().queue.enqueue(items.as_slice()).await?;
 
     let res = PushMessageResponse {
         status: "queued".to_string(),
+        message_id,
+        task_ids,
     };
 
     Ok(Response::builder(200).body(Body::from_json(&res)?).build())
 }
+
+fn make_batch_error(err: tide::Error) -> PushBatchItem {
+    PushBatchItem {
+        ok: false,
+        error_code: Some(err.status().into()),
+        description: Some(err.to_string()),
+        replayed: false,
+        result: None,
+    }
+}
+
+fn make_batch_result(res: PushMessageResponse, replayed: bool) -> PushBatchItem {
+    PushBatchItem {
+        ok: true,
+        error_code: None,
+        description: None,
+        replayed,
+        result: Some(res),
+    }
+}
+
+/// Validates a batch item, returns the message to save or the result of an
+/// earlier push with the same idempotency key.
+async fn prepare_batch_item(
+    ctx: &Context,
+    project: &model::Project,
+    item: serde_json::Value,
+) -> tide::Result<Result<model::Message, PushMessageResponse>> {
+    let data = serde_json::from_value::<PushMessageRequest>(item)
+        .map_err(|err| tide::Error::new(422, err))?;
+    validate_message(&data)?;
+    let send_time = resolve_send_time(&data, ctx.conf.push.max_delay)?;
+
+    let idempotency_key = match &data.idempotency_key {
+        None => None,
+        Some(key) => {
+            validate_idempotency_key(key)?;
+            if let Some(res) = find_idempotent_result(ctx, project.user_id, key).await? {
+                return Ok(Err(res));
+            }
+            Some(key.clone())
+        }
+    };
+
+    Ok(Ok(new_message(project, &data, send_time, idempotency_key)))
+}
+
+/// Pushes an array of messages. They are saved in one transaction and enqueued
+/// at once, each item reports its own result.
+pub async fn push_batch(mut req: Request<Arc<Context>>) -> tide::Result {
+    let project_id = push_key(&req)?;
+    let project = find_project(&req, &project_id).await?;
+
+    let body = req.body_bytes().await?;
+    if let Some(secret) = &project.signing_secret {
+        verify_signature(&req, &project, secret, &body).await?;
+    }
+    let items = serde_json::from_slice::<Vec<serde_json::Value>>(&body)
+        .map_err(|err| tide::Error::new(422, err))?;
+
+    let max_batch_size = req.state().conf.push.max_batch_size;
+    if items.is_empty() || items.len() > max_batch_size {
+        return Err(tide::Error::new(
+            400,
+            anyhow!("Batch must contain 1 to {} messages", max_batch_size),
+        ));
+    }
+
+    let mut results = Vec::with_capacity(items.len());
+    let mut messages = Vec::new();
+    let mut positions = Vec::new();
+    for item in items {
+        match prepare_batch_item(req.state(), &project, item).await {
+            Err(err) => results.push(make_batch_error(err)),
+            Ok(Err(res)) => results.push(make_batch_result(res, true)),
+            Ok(Ok(message)) => {
+                positions.push(results.len());
+                results.push(make_batch_error(tide::Error::new(
+                    500,
+                    anyhow!("Message was not saved"),
+                )));
+                messages.push(message);
+            }
+        }
+    }
+
+    // Save messages & tasks
+    let transports = req
+        .state()
+        .transport_model
+        .find_all_connected_by_user_id(project.user_id)
+        .await?;
+    let saved = model::insert_messages(&req.state().pool, &project, &messages, &transports).await?;
+
+    let mut queue_items = Vec::<(i64, i64)>::new();
+    for ((position, message), result) in positions.into_iter().zip(&messages).zip(saved) {
+        results[position] = match (result, &message.idempotency_key) {
+            (Err(err), _) => {
+                log::error!("failed to save batch message, {}", err);
+                continue;
+            }
+            (Ok(Some((message_id, task_ids))), _) => {
+                let ts = message.send_time.timestamp();
+                queue_items.extend(task_ids.iter().map(|task_id| (*task_id, ts)));
+
+                let res = PushMessageResponse {
+                    status: "queued".to_string(),
+                    message_id,
+                    task_ids,
+                };
+                make_batch_result(res, false)
+            }
+            // Repeated idempotency key within the batch or a concurrent request
+            (Ok(None), Some(key)) => {
+                match find_idempotent_result(req.state(), project.user_id, key).await? {
+                    Some(res) => make_batch_result(res, true),
+                    None => {
+                        make_batch_error(tide::Error::new(409, anyhow!("Idempotency key conflict")))
+                    }
+                }
+            }
+            (Ok(None), None) => continue,
+        };
+    }
+
+    // Adding to task queue
+    req.state().queue.enqueue(queue_items.as_slice()).await?;
+
+    let res = PushBatchResponse { results };
+
+    Ok(Response::builder(200).body(Body::from_json(&res)?).build())
+}
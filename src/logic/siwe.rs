@@ -0,0 +1,312 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use ethers_core::types::Address;
+
+const PREAMBLE_SUFFIX: &str = " wants you to sign in with your Ethereum account:";
+const URI_TAG: &str = "URI: ";
+const VERSION_TAG: &str = "Version: ";
+const CHAIN_ID_TAG: &str = "Chain ID: ";
+const NONCE_TAG: &str = "Nonce: ";
+const ISSUED_AT_TAG: &str = "Issued At: ";
+const EXPIRATION_TIME_TAG: &str = "Expiration Time: ";
+const NOT_BEFORE_TAG: &str = "Not Before: ";
+const REQUEST_ID_TAG: &str = "Request ID: ";
+const RESOURCES_TAG: &str = "Resources:";
+
+/// Sign-In with Ethereum (EIP-4361) message
+#[derive(Debug)]
+pub struct SiweMessage {
+    pub domain: String,
+    /// Address as written in the message, EIP-55 checksummed
+    pub address: String,
+    pub statement: Option<String>,
+    pub uri: String,
+    pub version: String,
+    pub chain_id: u64,
+    pub nonce: String,
+    pub issued_at: DateTime<Utc>,
+    pub expiration_time: Option<DateTime<Utc>>,
+    pub not_before: Option<DateTime<Utc>>,
+    pub request_id: Option<String>,
+    pub resources: Vec<String>,
+}
+
+fn parse_time(value: &str) -> Result<DateTime<Utc>> {
+    Ok(DateTime::parse_from_rfc3339(value)?.with_timezone(&Utc))
+}
+
+/// Returns the value of the next line if it starts with `tag`.
+fn tagged<'a>(lines: &[&'a str], idx: &mut usize, tag: &str) -> Option<&'a str> {
+    let value = lines.get(*idx)?.strip_prefix(tag)?;
+    *idx += 1;
+    Some(value)
+}
+
+fn required<'a>(lines: &[&'a str], idx: &mut usize, tag: &str) -> Result<&'a str> {
+    tagged(lines, idx, tag).ok_or_else(|| anyhow!("missing `{}` field", tag.trim_end()))
+}
+
+impl SiweMessage {
+    pub fn parse(message: &str) -> Result<Self> {
+        let lines: Vec<&str> = message.split('\n').collect();
+        if lines.len() < 4 {
+            return Err(anyhow!("invalid message"));
+        }
+
+        let domain = lines[0]
+            .strip_suffix(PREAMBLE_SUFFIX)
+            .ok_or_else(|| anyhow!("invalid preamble"))?;
+        let address = lines[1];
+        let parsed = address.parse::<Address>()?;
+        if ethers_core::utils::to_checksum(&parsed, None) != address {
+            return Err(anyhow!("address is not EIP-55 checksummed"));
+        }
+        if !lines[2].is_empty() {
+            return Err(anyhow!("invalid message"));
+        }
+
+        // The statement is optional, but the blank line that follows it is not
+        let mut idx = 3;
+        let mut statement = None;
+        if !lines[idx].is_empty() {
+            statement = Some(lines[idx].to_string());
+            idx += 1;
+        }
+        if lines.get(idx) != Some(&"") {
+            return Err(anyhow!("invalid message"));
+        }
+        idx += 1;
+
+        let uri = required(&lines, &mut idx, URI_TAG)?;
+        let version = required(&lines, &mut idx, VERSION_TAG)?;
+        let chain_id = required(&lines, &mut idx, CHAIN_ID_TAG)?.parse::<u64>()?;
+        let nonce = required(&lines, &mut idx, NONCE_TAG)?;
+        let issued_at = parse_time(required(&lines, &mut idx, ISSUED_AT_TAG)?)?;
+        let expiration_time = tagged(&lines, &mut idx, EXPIRATION_TIME_TAG)
+            .map(parse_time)
+            .transpose()?;
+        let not_before = tagged(&lines, &mut idx, NOT_BEFORE_TAG)
+            .map(parse_time)
+            .transpose()?;
+        let request_id = tagged(&lines, &mut idx, REQUEST_ID_TAG).map(String::from);
+
+        let mut resources = Vec::new();
+        if lines.get(idx) == Some(&RESOURCES_TAG) {
+            idx += 1;
+            while let Some(resource) = tagged(&lines, &mut idx, "- ") {
+                resources.push(resource.to_string());
+            }
+        }
+
+        if idx != lines.len() {
+            return Err(anyhow!("unexpected content after line {}", idx));
+        }
+
+        if version != "1" {
+            return Err(anyhow!("unsupported version {}", version));
+        }
+        if nonce.len() < 8 || !nonce.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(anyhow!("invalid nonce"));
+        }
+
+        Ok(SiweMessage {
+            domain: domain.to_string(),
+            address: address.to_string(),
+            statement,
+            uri: uri.to_string(),
+            version: version.to_string(),
+            chain_id,
+            nonce: nonce.to_string(),
+            issued_at,
+            expiration_time,
+            not_before,
+            request_id,
+            resources,
+        })
+    }
+
+    /// Checks the fields that bind the message to this service and to the current time.
+    pub fn validate(
+        &self,
+        domain: &str,
+        uri: &str,
+        chain_id: u64,
+        now: DateTime<Utc>,
+        clock_skew: chrono::Duration,
+    ) -> Result<()> {
+        if self.domain != domain {
+            return Err(anyhow!("domain mismatch"));
+        }
+        let base = uri.trim_end_matches('/');
+        if self.uri != base && !self.uri.starts_with(&format!("{}/", base)) {
+            return Err(anyhow!("uri mismatch"));
+        }
+        if self.chain_id != chain_id {
+            return Err(anyhow!("chain id mismatch"));
+        }
+        if self.issued_at > now + clock_skew {
+            return Err(anyhow!("message is issued in the future"));
+        }
+        if let Some(expiration_time) = self.expiration_time {
+            if expiration_time <= now {
+                return Err(anyhow!("message has expired"));
+            }
+        }
+        if let Some(not_before) = self.not_before {
+            if not_before > now + clock_skew {
+                return Err(anyhow!("message is not valid yet"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: &str = "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed";
+
+    fn message(address: &str, extra: &str) -> String {
+        format!(
+            "sps.example.com wants you to sign in with your Ethereum account:\n\
+             {}\n\
+             \n\
+             Sign in to Simple Push Service\n\
+             \n\
+             URI: https://sps.example.com/login\n\
+             Version: 1\n\
+             Chain ID: 1\n\
+             Nonce: abcdef1234\n\
+             Issued At: 2024-01-01T00:00:00Z{}",
+            address, extra
+        )
+    }
+
+    fn time(value: &str) -> DateTime<Utc> {
+        parse_time(value).unwrap()
+    }
+
+    fn validate(message: &SiweMessage, now: &str) -> Result<()> {
+        message.validate(
+            "sps.example.com",
+            "https://sps.example.com/",
+            1,
+            time(now),
+            chrono::Duration::seconds(60),
+        )
+    }
+
+    #[test]
+    fn parses_valid_message() {
+        let extra = "\nExpiration Time: 2024-01-02T00:00:00Z\n\
+                     Not Before: 2024-01-01T00:00:00Z\n\
+                     Request ID: req-1\n\
+                     Resources:\n\
+                     - https://sps.example.com/a\n\
+                     - https://sps.example.com/b";
+        let parsed = SiweMessage::parse(&message(ADDRESS, extra)).unwrap();
+
+        assert_eq!(parsed.domain, "sps.example.com");
+        assert_eq!(parsed.address, ADDRESS);
+        assert_eq!(
+            parsed.statement.as_deref(),
+            Some("Sign in to Simple Push Service")
+        );
+        assert_eq!(parsed.uri, "https://sps.example.com/login");
+        assert_eq!(parsed.chain_id, 1);
+        assert_eq!(parsed.nonce, "abcdef1234");
+        assert_eq!(parsed.issued_at, time("2024-01-01T00:00:00Z"));
+        assert_eq!(parsed.expiration_time, Some(time("2024-01-02T00:00:00Z")));
+        assert_eq!(parsed.not_before, Some(time("2024-01-01T00:00:00Z")));
+        assert_eq!(parsed.request_id.as_deref(), Some("req-1"));
+        assert_eq!(parsed.resources.len(), 2);
+        assert!(validate(&parsed, "2024-01-01T12:00:00Z").is_ok());
+    }
+
+    #[test]
+    fn parses_message_without_statement() {
+        // Both blank lines around the statement remain
+        let text = message(ADDRESS, "").replace("Sign in to Simple Push Service\n", "");
+        let parsed = SiweMessage::parse(&text).unwrap();
+        assert!(parsed.statement.is_none());
+    }
+
+    #[test]
+    fn rejects_missing_field() {
+        let text = message(ADDRESS, "").replace("Nonce: abcdef1234\n", "");
+        assert!(SiweMessage::parse(&text).is_err());
+    }
+
+    #[test]
+    fn rejects_extra_field() {
+        assert!(SiweMessage::parse(&message(ADDRESS, "\nFoo: bar")).is_err());
+        assert!(SiweMessage::parse(&message(ADDRESS, "\n")).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_checksum() {
+        let address = "0x5aaeb6053F3E94C9b9A09f33669435E7Ef1BeAed";
+        assert!(SiweMessage::parse(&message(address, "")).is_err());
+
+        let lowercase = ADDRESS.to_lowercase();
+        assert!(SiweMessage::parse(&message(&lowercase, "")).is_err());
+    }
+
+    #[test]
+    fn rejects_unsupported_version_and_short_nonce() {
+        let text = message(ADDRESS, "").replace("Version: 1", "Version: 2");
+        assert!(SiweMessage::parse(&text).is_err());
+
+        let text = message(ADDRESS, "").replace("abcdef1234", "abc");
+        assert!(SiweMessage::parse(&text).is_err());
+    }
+
+    #[test]
+    fn rejects_expired_message() {
+        let extra = "\nExpiration Time: 2024-01-02T00:00:00Z";
+        let parsed = SiweMessage::parse(&message(ADDRESS, extra)).unwrap();
+
+        assert!(validate(&parsed, "2024-01-01T23:59:59Z").is_ok());
+        assert!(validate(&parsed, "2024-01-02T00:00:00Z").is_err());
+    }
+
+    #[test]
+    fn rejects_message_before_not_before() {
+        let extra = "\nNot Before: 2024-01-01T01:00:00Z";
+        let parsed = SiweMessage::parse(&message(ADDRESS, extra)).unwrap();
+
+        assert!(validate(&parsed, "2024-01-01T00:30:00Z").is_err());
+        assert!(validate(&parsed, "2024-01-01T00:59:30Z").is_ok());
+    }
+
+    #[test]
+    fn rejects_message_issued_in_the_future() {
+        let parsed = SiweMessage::parse(&message(ADDRESS, "")).unwrap();
+        assert!(validate(&parsed, "2023-12-31T23:00:00Z").is_err());
+    }
+
+    #[test]
+    fn rejects_domain_uri_and_chain_mismatch() {
+        let parsed = SiweMessage::parse(&message(ADDRESS, "")).unwrap();
+        let now = time("2024-01-01T12:00:00Z");
+        let skew = chrono::Duration::seconds(60);
+
+        assert!(parsed
+            .validate("evil.example.com", "https://sps.example.com", 1, now, skew)
+            .is_err());
+        assert!(parsed
+            .validate("sps.example.com", "https://evil.example.com", 1, now, skew)
+            .is_err());
+        assert!(parsed
+            .validate("sps.example.com", "https://sps.example.co", 1, now, skew)
+            .is_err());
+        assert!(parsed
+            .validate("sps.example.com", "https://sps.example.com", 5, now, skew)
+            .is_err());
+        assert!(parsed
+            .validate("sps.example.com", "https://sps.example.com", 1, now, skew)
+            .is_ok());
+    }
+}
@@ -0,0 +1,40 @@
+use crate::job::{TelegramUpdate, TelegramUpdateHandler};
+use crate::service::Context;
+use anyhow::anyhow;
+use async_std::sync::Arc;
+use tide::{Body, Request, Response};
+
+/// Compares in time that depends only on the length, not on the content.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Receives bot updates from Telegram in webhook mode. The handler is shared
+/// by all requests, so that it keeps its cached bot username.
+pub async fn telegram_webhook(
+    mut req: Request<Arc<Context>>,
+    handler: &TelegramUpdateHandler,
+) -> tide::Result {
+    let secret = req.state().conf.telegram.webhook_secret.as_deref();
+    let token = req
+        .header("X-Telegram-Bot-Api-Secret-Token")
+        .map(|value| value.last().as_str());
+    let authorized = match (secret, token) {
+        (Some(secret), Some(token)) => constant_time_eq(secret.as_bytes(), token.as_bytes()),
+        _ => false,
+    };
+    if !authorized {
+        return Err(tide::Error::new(401, anyhow!("Unauthorized")));
+    }
+
+    let update: TelegramUpdate = req.body_json().await?;
+
+    // Telegram redelivers updates that fail, so errors are only logged
+    if let Err(err) = handler.handle_update(&update).await {
+        log::error!("[TelegramBot] failed to handle message, {}", err);
+    }
+
+    Ok(Response::builder(200)
+        .body(Body::from_json(&serde_json::json!({}))?)
+        .build())
+}
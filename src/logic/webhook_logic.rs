@@ -0,0 +1,136 @@
+use crate::model::{self, transport_type};
+use crate::service::Context;
+use crate::transport;
+use crate::types::*;
+use anyhow::{anyhow, Result};
+use async_std::sync::Arc;
+use http_types::Url;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use tide::{Body, Request, Response};
+
+const MAX_URL_LENGTH: usize = 1024;
+const SLACK_URL_PREFIXES: &[&str] = &["https://hooks.slack.com/"];
+const DISCORD_URL_PREFIXES: &[&str] = &[
+    "https://discord.com/api/webhooks/",
+    "https://discordapp.com/api/webhooks/",
+];
+
+fn gen_secret() -> String {
+    let buf: Vec<u8> = thread_rng().sample_iter(&Alphanumeric).take(64).collect();
+    String::from_utf8_lossy(buf.as_slice()).to_string()
+}
+
+fn validate_url(url: &str, prefixes: &[&str]) -> tide::Result<()> {
+    let invalid_url = || tide::Error::new(400, anyhow!("Invalid webhook url"));
+    let parsed = Url::parse(url).map_err(|_| invalid_url())?;
+    if !matches!(parsed.scheme(), "http" | "https") || url.len() > MAX_URL_LENGTH {
+        return Err(invalid_url());
+    }
+
+    if !prefixes.is_empty() && !prefixes.iter().any(|prefix| url.starts_with(prefix)) {
+        return Err(invalid_url());
+    }
+
+    Ok(())
+}
+
+/// Creates or updates the url based transport of the user.
+async fn save_url_transport(
+    ctx: &Context,
+    wallet_address: &str,
+    kind: &str,
+    url: &str,
+    secret: Option<&str>,
+) -> Result<()> {
+    let user = ctx
+        .user_model
+        .find_one_by_wallet_address(wallet_address)
+        .await?;
+
+    let transport_model = &ctx.transport_model;
+    let result = transport_model
+        .find_one_by_user_id_type(user.id, kind)
+        .await;
+    match result {
+        Ok(_) => {
+            transport_model
+                .update_chat_id(user.id, None, kind, url)
+                .await?;
+            if let Some(secret) = secret {
+                transport_model.update_secret(user.id, kind, secret).await?;
+            }
+            transport_model
+                .update_connected(user.id, kind, true)
+                .await?;
+        }
+        Err(err) => {
+            if !model::is_not_found_record_err(&err) {
+                return Err(err);
+            }
+
+            let mut transport = model::Transport::new(user.id, kind);
+            transport.connected = true;
+            transport.chat_id = Some(url.to_string());
+            transport.secret = secret.map(String::from);
+            transport_model.insert(&transport).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Sets the webhook url of the user and issues a new signing secret.
+pub async fn bind_webhook(mut req: Request<Arc<Context>>) -> tide::Result {
+    let data: BindWebhookRequest = req.body_json().await?;
+    validate_url(&data.url, &[])?;
+    transport::webhook::check_url(&data.url, &req.state().conf.webhook.allowed_hosts)
+        .await
+        .map_err(|err| tide::Error::new(400, anyhow!("Invalid webhook url, {}", err)))?;
+
+    let secret = gen_secret();
+    let wallet_address = req.ext::<String>().unwrap();
+    save_url_transport(
+        req.state(),
+        wallet_address,
+        transport_type::WEBHOOK,
+        &data.url,
+        Some(&secret),
+    )
+    .await?;
+
+    let res = BindWebhookResponse {
+        url: data.url,
+        secret,
+    };
+
+    Ok(Response::builder(200).body(Body::from_json(&res)?).build())
+}
+
+async fn bind_incoming_webhook(
+    mut req: Request<Arc<Context>>,
+    kind: &str,
+    prefixes: &[&str],
+) -> tide::Result {
+    let data: BindWebhookRequest = req.body_json().await?;
+    validate_url(&data.url, prefixes)?;
+
+    let wallet_address = req.ext::<String>().unwrap();
+    save_url_transport(req.state(), wallet_address, kind, &data.url, None).await?;
+
+    let res = Transport {
+        transport_type: kind.to_string(),
+        chat_id: Some(data.url),
+        connected: true,
+    };
+
+    Ok(Response::builder(200).body(Body::from_json(&res)?).build())
+}
+
+pub async fn bind_slack(req: Request<Arc<Context>>) -> tide::Result {
+    bind_incoming_webhook(req, transport_type::SLACK, SLACK_URL_PREFIXES).await
+}
+
+pub async fn bind_discord(req: Request<Arc<Context>>) -> tide::Result {
+    bind_incoming_webhook(req, transport_type::DISCORD, DISCORD_URL_PREFIXES).await
+}
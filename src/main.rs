@@ -1,6 +1,7 @@
 use anyhow::Result;
+use async_std::sync::Arc;
 use chrono::Datelike;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use simplelog::*;
 use sps::config;
 use sps::handler;
@@ -16,6 +17,24 @@ struct Args {
     /// Configuration file
     #[clap(short, long, default_value = "etc/sps.toml")]
     config: String,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Inspect or requeue tasks in the dead letter queue
+    #[clap(subcommand)]
+    DeadLetter(DeadLetterCommand),
+}
+
+#[derive(Subcommand)]
+enum DeadLetterCommand {
+    /// List failed tasks
+    List,
+    /// Move a failed task back to the task queue
+    Requeue { task_id: i64 },
 }
 
 fn init_logger() {
@@ -43,6 +62,31 @@ fn init_logger() {
     .unwrap();
 }
 
+async fn run_dead_letter_command(ctx: Arc<Context>, command: DeadLetterCommand) -> Result<()> {
+    let dead_letter_queue = job::DeadLetterQueue::new(ctx);
+    match command {
+        DeadLetterCommand::List => {
+            for entry in dead_letter_queue.list().await? {
+                println!(
+                    "task_id: {}, message_id: {}, transport: {}, retry_count: {}, failed_time: {}, reason: {}",
+                    entry.task.id,
+                    entry.task.message_id,
+                    entry.task.transport_type,
+                    entry.task.retry_count,
+                    entry.failed_time,
+                    entry.task.reason.unwrap_or_default()
+                );
+            }
+        }
+        DeadLetterCommand::Requeue { task_id } => {
+            dead_letter_queue.requeue(task_id).await?;
+            println!("task {} requeued", task_id);
+        }
+    }
+
+    Ok(())
+}
+
 #[async_std::main]
 async fn main() -> Result<()> {
     init_logger();
@@ -51,9 +95,16 @@ async fn main() -> Result<()> {
     let c = config::must_load(&args.config).await;
 
     let ctx = Context::make_pointer(&c).await?;
+    if let Some(Command::DeadLetter(command)) = args.command {
+        return run_dead_letter_command(ctx, command).await;
+    }
+
     let pusher = job::Pusher::new(ctx.clone(), 12);
     pusher.start()?;
 
+    let sweeper = job::Sweeper::new(ctx.clone());
+    sweeper.start()?;
+
     let tg_bot = job::TelegramBot::new(ctx.clone());
     tg_bot.start()?;
 
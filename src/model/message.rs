@@ -1,28 +1,70 @@
 use anyhow::Result;
 use sqlx::{Pool, Postgres};
 
-#[derive(sqlx::FromRow)]
+/// Markup of the message content.
+pub mod message_format {
+    pub const PLAIN: &str = "plain";
+    pub const MARKDOWN: &str = "markdown";
+    pub const HTML: &str = "html";
+
+    pub const ALL: &[&str] = &[PLAIN, MARKDOWN, HTML];
+}
+
+/// What to do with content that exceeds the maximum length of a transport.
+pub mod message_overflow {
+    /// Deliver the content in several ordered parts
+    pub const SPLIT: &str = "split";
+    /// Cut the content and mark the cut
+    pub const TRUNCATE: &str = "truncate";
+
+    pub const ALL: &[&str] = &[SPLIT, TRUNCATE];
+}
+
+#[derive(Clone, sqlx::FromRow)]
 #[sqlx(type_name = "message")]
 pub struct Message {
     pub id: i64,
     pub user_id: i64,
+    /// Project the message was pushed to, unset for messages from before projects
+    pub project: Option<i64>,
     pub title: String,
     pub content: String,
+    pub format: String,
+    pub overflow: String,
+    pub send_time: chrono::DateTime<chrono::Utc>,
+    /// Key supplied by the client to deduplicate retried push requests
+    pub idempotency_key: Option<String>,
     pub creation_time: chrono::DateTime<chrono::Utc>,
 }
 
 impl Message {
     pub fn new(user_id: i64, title: &str, content: &str) -> Self {
+        let now = chrono::Utc::now();
         Message {
             id: 0,
             user_id,
+            project: None,
             title: String::from(title),
             content: String::from(content),
-            creation_time: chrono::Utc::now(),
+            format: String::from(message_format::PLAIN),
+            overflow: String::from(message_overflow::SPLIT),
+            send_time: now,
+            idempotency_key: None,
+            creation_time: now,
         }
     }
 }
 
+/// Conditions of a message history query, unset fields do not filter.
+#[derive(Default)]
+pub struct MessageFilter {
+    pub before_id: Option<i64>,
+    pub since: Option<chrono::DateTime<chrono::Utc>>,
+    pub until: Option<chrono::DateTime<chrono::Utc>>,
+    pub task_state: Option<String>,
+    pub search: Option<String>,
+}
+
 pub struct MessageModel {
     pool: Pool<Postgres>,
 }
@@ -33,12 +75,13 @@ impl MessageModel {
     }
 
     pub async fn insert(&self, data: &Message) -> Result<i64> {
-        let query = r#"INSERT INTO "message"("user_id", "title", "content", "creation_time") VALUES($1, $2, $3, $4) RETURNING "id""#;
+        let query = r#"INSERT INTO "message"("user_id", "title", "content", "send_time", "creation_time") VALUES($1, $2, $3, $4, $5) RETURNING "id""#;
         let row: (i64,) = sqlx::query_as(query)
             .bind(data.user_id)
             .bind(&data.title)
             .bind(&data.content)
-            .bind(&data.creation_time)
+            .bind(data.send_time)
+            .bind(data.creation_time)
             .fetch_one(&self.pool)
             .await?;
         Ok(row.0)
@@ -46,17 +89,53 @@ impl MessageModel {
 
     pub async fn find_one_by_id(&self, id: i64) -> Result<Message> {
         let query = r#"SELECT * FROM "message" WHERE "id" = $1"#;
+        let message = sqlx::query_as(query).bind(id).fetch_one(&self.pool).await?;
+        Ok(message)
+    }
+
+    pub async fn find_one_by_idempotency_key(
+        &self,
+        user_id: i64,
+        idempotency_key: &str,
+    ) -> Result<Message> {
+        let query = r#"SELECT * FROM "message" WHERE "user_id" = $1 AND "idempotency_key" = $2"#;
         let message = sqlx::query_as(query)
-            .bind(id)
+            .bind(user_id)
+            .bind(idempotency_key)
             .fetch_one(&self.pool)
             .await?;
         Ok(message)
     }
 
-    pub async fn find_all_by_user_id(&self, user_id: i64) -> Result<Vec<Message>> {
-        let query = r#"SELECT * FROM "message" WHERE "user_id" = $1 ORDER BY "id" DESC"#;
+    /// Releases the idempotency key of a message, so that it can be used again.
+    pub async fn clear_idempotency_key(&self, id: i64) -> Result<()> {
+        let query = r#"UPDATE "message" SET "idempotency_key" = NULL WHERE "id" = $1"#;
+        sqlx::query(query).bind(id).execute(&self.pool).await?;
+        Ok(())
+    }
+
+    /// Finds at most `limit` messages of the user matching `filter`, newest first.
+    pub async fn find_page_by_user_id(
+        &self,
+        user_id: i64,
+        filter: &MessageFilter,
+        limit: i64,
+    ) -> Result<Vec<Message>> {
+        let query = r#"SELECT * FROM "message" WHERE "user_id" = $1
+            AND ($2::int8 IS NULL OR "id" < $2)
+            AND ($3::timestamptz IS NULL OR "creation_time" >= $3)
+            AND ($4::timestamptz IS NULL OR "creation_time" < $4)
+            AND ($5::varchar IS NULL OR EXISTS (SELECT 1 FROM "task" WHERE "task"."message_id" = "message"."id" AND "task"."state" = $5))
+            AND ($6::text IS NULL OR to_tsvector('simple', "title" || ' ' || "content") @@ plainto_tsquery('simple', $6))
+            ORDER BY "id" DESC LIMIT $7"#;
         let messages = sqlx::query_as(query)
             .bind(user_id)
+            .bind(filter.before_id)
+            .bind(filter.since)
+            .bind(filter.until)
+            .bind(&filter.task_state)
+            .bind(&filter.search)
+            .bind(limit)
             .fetch_all(&self.pool)
             .await?;
         Ok(messages)
@@ -1,21 +1,22 @@
 pub mod message;
+pub mod project;
+pub mod push_key;
 pub mod task;
 pub mod transaction;
 pub mod transport;
 pub mod user;
 
 pub use message::*;
+pub use project::*;
+pub use push_key::*;
 pub use task::*;
 pub use transaction::*;
 pub use transport::*;
 pub use user::*;
 
 pub fn is_not_found_record_err(err: &anyhow::Error) -> bool {
-    return match err.downcast_ref::<sqlx::Error>() {
-        None => false,
-        Some(err) => match err {
-            sqlx::Error::RowNotFound => true,
-            _ => false,
-        },
-    };
+    matches!(
+        err.downcast_ref::<sqlx::Error>(),
+        Some(sqlx::Error::RowNotFound)
+    )
 }
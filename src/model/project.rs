@@ -0,0 +1,96 @@
+use anyhow::Result;
+use sqlx::{Pool, Postgres};
+
+pub const DEFAULT_PROJECT_NAME: &str = "default";
+
+/// A named push endpoint of a user. Messages pushed to a project are delivered
+/// to the chosen transport types only, or to all transports if none are chosen.
+#[derive(sqlx::FromRow)]
+#[sqlx(type_name = "project")]
+pub struct Project {
+    pub id: i64,
+    pub user_id: i64,
+    pub name: String,
+    pub transport_types: Option<Vec<String>>,
+    /// Push requests must be signed with this secret when set
+    pub signing_secret: Option<String>,
+    pub creation_time: chrono::DateTime<chrono::Utc>,
+}
+
+impl Project {
+    pub fn new(user_id: i64, name: &str, transport_types: Option<Vec<String>>) -> Self {
+        Project {
+            id: 0,
+            user_id,
+            name: String::from(name),
+            transport_types,
+            signing_secret: None,
+            creation_time: chrono::Utc::now(),
+        }
+    }
+
+    /// Whether messages of the project are delivered by transports of this type.
+    pub fn routes_to(&self, transport_type: &str) -> bool {
+        match &self.transport_types {
+            None => true,
+            Some(types) => types.iter().any(|t| t == transport_type),
+        }
+    }
+}
+
+pub struct ProjectModel {
+    pool: Pool<Postgres>,
+}
+
+impl ProjectModel {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        ProjectModel { pool }
+    }
+
+    pub async fn find_one_by_id(&self, id: i64) -> Result<Project> {
+        let query = r#"SELECT * FROM "project" WHERE "id" = $1"#;
+        let project = sqlx::query_as(query).bind(id).fetch_one(&self.pool).await?;
+        Ok(project)
+    }
+
+    /// Finds the project by any of its valid push keys.
+    pub async fn find_one_by_project_id(&self, project_id: &str) -> Result<Project> {
+        let query = r#"SELECT "project".* FROM "project" JOIN "push_key" ON "push_key"."project" = "project"."id" WHERE "push_key"."key" = $1 AND ("push_key"."expire_time" IS NULL OR "push_key"."expire_time" > $2)"#;
+        let project = sqlx::query_as(query)
+            .bind(project_id)
+            .bind(chrono::Utc::now())
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(project)
+    }
+
+    pub async fn find_all_by_user_id(&self, user_id: i64) -> Result<Vec<Project>> {
+        let query = r#"SELECT * FROM "project" WHERE "user_id" = $1 ORDER BY "id""#;
+        let projects = sqlx::query_as(query)
+            .bind(user_id)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(projects)
+    }
+
+    pub async fn update_signing_secret(&self, id: i64, signing_secret: Option<&str>) -> Result<()> {
+        let query = r#"UPDATE "project" SET "signing_secret" = $2 WHERE "id" = $1"#;
+        sqlx::query(query)
+            .bind(id)
+            .bind(signing_secret)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn update(&self, data: &Project) -> Result<()> {
+        let query = r#"UPDATE "project" SET "name" = $2, "transport_types" = $3 WHERE "id" = $1"#;
+        sqlx::query(query)
+            .bind(data.id)
+            .bind(&data.name)
+            .bind(&data.transport_types)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,53 @@
+use anyhow::Result;
+use sqlx::{Pool, Postgres};
+
+/// Minimum number of seconds between two updates of `last_used_time`.
+const LAST_USED_RESOLUTION: i64 = 60;
+
+/// A key that can be used to push messages. The current key of a user has no
+/// expire time, replaced keys stay valid until they expire.
+#[derive(sqlx::FromRow)]
+#[sqlx(type_name = "push_key")]
+pub struct PushKey {
+    pub id: i64,
+    pub user_id: i64,
+    pub project: i64,
+    pub key: String,
+    pub last_used_time: Option<chrono::DateTime<chrono::Utc>>,
+    pub expire_time: Option<chrono::DateTime<chrono::Utc>>,
+    pub creation_time: chrono::DateTime<chrono::Utc>,
+}
+
+pub struct PushKeyModel {
+    pool: Pool<Postgres>,
+}
+
+impl PushKeyModel {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        PushKeyModel { pool }
+    }
+
+    /// Returns the keys of the project that are still valid, newest first.
+    pub async fn find_all_valid_by_project(&self, project: i64) -> Result<Vec<PushKey>> {
+        let query = r#"SELECT * FROM "push_key" WHERE "project" = $1 AND ("expire_time" IS NULL OR "expire_time" > $2) ORDER BY "id" DESC"#;
+        let keys = sqlx::query_as(query)
+            .bind(project)
+            .bind(chrono::Utc::now())
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(keys)
+    }
+
+    /// Records that the key was used, at most once per `LAST_USED_RESOLUTION`.
+    pub async fn touch(&self, key: &str) -> Result<()> {
+        let now = chrono::Utc::now();
+        let query = r#"UPDATE "push_key" SET "last_used_time" = $2 WHERE "key" = $1 AND ("last_used_time" IS NULL OR "last_used_time" < $3)"#;
+        sqlx::query(query)
+            .bind(key)
+            .bind(now)
+            .bind(now - chrono::Duration::seconds(LAST_USED_RESOLUTION))
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
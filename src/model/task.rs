@@ -20,7 +20,11 @@ pub struct Task {
     pub transport_type: String,
     pub state: String,
     pub retry_count: i32,
+    /// Parts of a split message delivered so far, they are skipped on retry
+    pub delivered_parts: i32,
     pub reason: Option<String>,
+    pub last_attempt_time: Option<chrono::DateTime<chrono::Utc>>,
+    pub delivered_time: Option<chrono::DateTime<chrono::Utc>>,
     pub creation_time: chrono::DateTime<chrono::Utc>,
 }
 
@@ -37,7 +41,10 @@ impl Task {
             transport_type: transport.transport_type.clone(),
             state: self::state::PENDING.into(),
             retry_count: 0,
+            delivered_parts: 0,
             reason: None,
+            last_attempt_time: None,
+            delivered_time: None,
             creation_time: chrono::Utc::now(),
         }
     }
@@ -63,7 +70,7 @@ impl TaskModel {
             .bind(data.state.clone())
             .bind(data.retry_count)
             .bind(&data.reason)
-            .bind(&data.creation_time)
+            .bind(data.creation_time)
             .fetch_one(&self.pool)
             .await?;
         Ok(row.0)
@@ -75,10 +82,41 @@ impl TaskModel {
         Ok(task)
     }
 
+    pub async fn find_all_by_message_id(&self, message_id: i64) -> Result<Vec<Task>> {
+        let query = r#"SELECT * FROM "task" WHERE "message_id" = $1 ORDER BY "id""#;
+        let tasks = sqlx::query_as(query)
+            .bind(message_id)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(tasks)
+    }
+
+    /// Finds pending or retrying tasks created before `before`, in ascending id order
+    /// starting after `after_id`. Returns the task ids with the send time of their message.
+    pub async fn find_unfinished_before(
+        &self,
+        before: chrono::DateTime<chrono::Utc>,
+        after_id: i64,
+        limit: i64,
+    ) -> Result<Vec<(i64, chrono::DateTime<chrono::Utc>)>> {
+        let query = r#"SELECT "task"."id", "message"."send_time" FROM "task" JOIN "message" ON "message"."id" = "task"."message_id" WHERE "task"."state" IN ($1, $2) AND "task"."creation_time" < $3 AND "task"."id" > $4 ORDER BY "task"."id" LIMIT $5"#;
+        let tasks = sqlx::query_as(query)
+            .bind(self::state::PENDING)
+            .bind(self::state::RETRYING)
+            .bind(before)
+            .bind(after_id)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(tasks)
+    }
+
     pub async fn set_done(&self, id: i64) -> Result<()> {
-        let query = r#"UPDATE "task" SET "state" = $1 WHERE "id" = $2"#;
+        let now = chrono::Utc::now();
+        let query = r#"UPDATE "task" SET "state" = $1, "last_attempt_time" = $2, "delivered_time" = $2 WHERE "id" = $3"#;
         sqlx::query(query)
             .bind(self::state::DONE)
+            .bind(now)
             .bind(id)
             .execute(&self.pool)
             .await?;
@@ -86,19 +124,100 @@ impl TaskModel {
         Ok(())
     }
 
+    pub async fn update_delivered_parts(&self, id: i64, delivered_parts: i32) -> Result<()> {
+        let query = r#"UPDATE "task" SET "delivered_parts" = $1 WHERE "id" = $2"#;
+        sqlx::query(query)
+            .bind(delivered_parts)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Moves the unfinished tasks of a chat to `new_chat_id`.
+    pub async fn update_chat_id_by_chat_id(
+        &self,
+        transport_type: &str,
+        chat_id: &str,
+        new_chat_id: &str,
+    ) -> Result<()> {
+        let query = r#"UPDATE "task" SET "chat_id" = $1 WHERE "transport_type" = $2 AND "chat_id" = $3 AND "state" IN ($4, $5)"#;
+        sqlx::query(query)
+            .bind(new_chat_id)
+            .bind(transport_type)
+            .bind(chat_id)
+            .bind(self::state::PENDING)
+            .bind(self::state::RETRYING)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
     pub async fn update_retry_state(&self, id: i64, reason: &str) -> Result<()> {
-        let reason = match reason.len() > 255 {
-            false => reason,
-            true => &reason[..255],
-        };
+        let now = chrono::Utc::now();
+        let query = r#"UPDATE "task" SET "state" = $1, "retry_count" = "retry_count" + 1, "reason" = $2, "last_attempt_time" = $3 WHERE "id" = $4"#;
+        sqlx::query(query)
+            .bind(self::state::RETRYING)
+            .bind(truncate_reason(reason))
+            .bind(now)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Like `update_retry_state`, but without counting the attempt against the
+    /// retry budget.
+    pub async fn update_rate_limited_state(&self, id: i64, reason: &str) -> Result<()> {
+        let now = chrono::Utc::now();
+        let query = r#"UPDATE "task" SET "state" = $1, "reason" = $2, "last_attempt_time" = $3 WHERE "id" = $4"#;
+        sqlx::query(query)
+            .bind(self::state::RETRYING)
+            .bind(truncate_reason(reason))
+            .bind(now)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
 
-        let query = r#"UPDATE "task" SET "retry_count" = "retry_count" + 1 AND "reason" = $1 WHERE "id" = $2"#;
+    pub async fn set_fail(&self, id: i64, reason: &str) -> Result<()> {
+        let now = chrono::Utc::now();
+        let query = r#"UPDATE "task" SET "state" = $1, "reason" = $2, "last_attempt_time" = $3 WHERE "id" = $4"#;
         sqlx::query(query)
-            .bind(reason)
+            .bind(self::state::FAIL)
+            .bind(truncate_reason(reason))
+            .bind(now)
             .bind(id)
             .execute(&self.pool)
             .await?;
 
         Ok(())
     }
+
+    /// Resets a failed task so that it can be delivered again. Returns `false` if the task
+    /// does not exist or is not in the `fail` state.
+    pub async fn reset_failed(&self, id: i64) -> Result<bool> {
+        let query =
+            r#"UPDATE "task" SET "state" = $1, "retry_count" = 0 WHERE "id" = $2 AND "state" = $3"#;
+        let result = sqlx::query(query)
+            .bind(self::state::PENDING)
+            .bind(id)
+            .bind(self::state::FAIL)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+fn truncate_reason(reason: &str) -> &str {
+    match reason.char_indices().nth(255) {
+        None => reason,
+        Some((idx, _)) => &reason[..idx],
+    }
 }
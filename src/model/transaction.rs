@@ -1,32 +1,45 @@
-use crate::model::{task, Transport};
+use crate::model::{project, task, Message, Project, Transport, User};
 use anyhow::Result;
-use sqlx::{Pool, Postgres};
+use sqlx::{Pool, Postgres, Transaction};
 
-pub async fn insert_message(
-    pool: &Pool<Postgres>,
-    user_id: i64,
-    title: &str,
-    content: &str,
-    transports: &Vec<Transport>,
-) -> Result<Vec<i64>> {
-    let mut tx = pool.begin().await?;
-    let creation_time = chrono::Utc::now();
-
-    let query = r#"INSERT INTO "message"("user_id", "title", "content", "creation_time") VALUES($1, $2, $3, $4) RETURNING "id""#;
-    let row: (i64,) = sqlx::query_as(query)
+/// Saves a message with a task for each transport the project routes to.
+/// Returns `None` if another message of the user holds its idempotency key.
+async fn insert_message_with_tasks(
+    tx: &mut Transaction<'_, Postgres>,
+    project: &Project,
+    message: &Message,
+    transports: &[Transport],
+    creation_time: chrono::DateTime<chrono::Utc>,
+) -> Result<Option<(i64, Vec<i64>)>> {
+    let user_id = project.user_id;
+    let query = r#"INSERT INTO "message"("user_id", "project", "title", "content", "format", "overflow", "send_time", "idempotency_key", "creation_time") VALUES($1, $2, $3, $4, $5, $6, $7, $8, $9)
+        ON CONFLICT ("user_id", "idempotency_key") WHERE "idempotency_key" IS NOT NULL DO NOTHING RETURNING "id""#;
+    let row: Option<(i64,)> = sqlx::query_as(query)
         .bind(user_id)
-        .bind(title)
-        .bind(content)
-        .bind(&creation_time)
-        .fetch_one(&mut tx)
+        .bind(project.id)
+        .bind(&message.title)
+        .bind(&message.content)
+        .bind(&message.format)
+        .bind(&message.overflow)
+        .bind(message.send_time)
+        .bind(&message.idempotency_key)
+        .bind(creation_time)
+        .fetch_optional(&mut *tx)
         .await?;
 
-    let message_id = row.0;
+    let message_id = match row {
+        Some(row) => row.0,
+        None => return Ok(None),
+    };
     let retry_count = 0i32;
     let reason: Option<String> = None;
 
     let mut ids = Vec::<i64>::new();
     for transport in transports {
+        if !project.routes_to(&transport.transport_type) {
+            continue;
+        }
+
         let chat_id = transport.chat_id.as_ref().unwrap();
         let query = r#"INSERT INTO "task"("message_id", "user_id", "chat_id", "transport", "transport_type", "state", "retry_count", "reason", "creation_time") VALUES($1, $2, $3, $4, $5, $6, $7, $8, $9) RETURNING "id""#;
         let row: (i64,) = sqlx::query_as(query)
@@ -38,14 +51,190 @@ pub async fn insert_message(
             .bind(task::state::PENDING)
             .bind(retry_count)
             .bind(&reason)
-            .bind(&creation_time)
-            .fetch_one(&mut tx)
+            .bind(creation_time)
+            .fetch_one(&mut *tx)
             .await?;
 
         ids.push(row.0);
     }
 
+    Ok(Some((message_id, ids)))
+}
+
+/// Saves a message pushed to the project, with a task for each transport the
+/// project routes to. Returns `None` if the idempotency key is already taken.
+pub async fn insert_message(
+    pool: &Pool<Postgres>,
+    project: &Project,
+    message: &Message,
+    transports: &[Transport],
+) -> Result<Option<(i64, Vec<i64>)>> {
+    let mut tx = pool.begin().await?;
+    let creation_time = chrono::Utc::now();
+
+    let result =
+        insert_message_with_tasks(&mut tx, project, message, transports, creation_time).await?;
+
+    tx.commit().await?;
+
+    Ok(result)
+}
+
+/// Saves all messages in a single transaction, the result of each message is
+/// the same as for `insert_message`. Each message is saved under a savepoint,
+/// so that a message that fails does not abort the others.
+pub async fn insert_messages(
+    pool: &Pool<Postgres>,
+    project: &Project,
+    messages: &[Message],
+    transports: &[Transport],
+) -> Result<Vec<Result<Option<(i64, Vec<i64>)>>>> {
+    let mut tx = pool.begin().await?;
+    let creation_time = chrono::Utc::now();
+
+    let mut results = Vec::with_capacity(messages.len());
+    for message in messages {
+        sqlx::query("SAVEPOINT batch_item").execute(&mut tx).await?;
+        let result =
+            insert_message_with_tasks(&mut tx, project, message, transports, creation_time).await;
+        let query = match result {
+            Ok(_) => "RELEASE SAVEPOINT batch_item",
+            Err(_) => "ROLLBACK TO SAVEPOINT batch_item",
+        };
+        sqlx::query(query).execute(&mut tx).await?;
+        results.push(result);
+    }
+
+    tx.commit().await?;
+
+    Ok(results)
+}
+
+async fn insert_push_key(
+    tx: &mut Transaction<'_, Postgres>,
+    user_id: i64,
+    project: i64,
+    key: &str,
+    creation_time: chrono::DateTime<chrono::Utc>,
+) -> Result<()> {
+    let query = r#"INSERT INTO "push_key"("user_id", "project", "key", "creation_time") VALUES($1, $2, $3, $4)"#;
+    sqlx::query(query)
+        .bind(user_id)
+        .bind(project)
+        .bind(key)
+        .bind(creation_time)
+        .execute(tx)
+        .await?;
+    Ok(())
+}
+
+/// Saves a new user along with its default project, which routes to all
+/// transports and owns the push key in `project_id`.
+pub async fn insert_user(pool: &Pool<Postgres>, data: &User) -> Result<i64> {
+    let mut tx = pool.begin().await?;
+
+    let query = r#"INSERT INTO "user"("open_id", "project_id", "wallet_address", "creation_time") VALUES($1, $2, $3, $4) RETURNING "id""#;
+    let row: (i64,) = sqlx::query_as(query)
+        .bind(data.open_id)
+        .bind(&data.project_id)
+        .bind(&data.wallet_address)
+        .bind(data.creation_time)
+        .fetch_one(&mut tx)
+        .await?;
+
+    let user_id = row.0;
+    let query = r#"INSERT INTO "project"("user_id", "name", "creation_time") VALUES($1, $2, $3) RETURNING "id""#;
+    let row: (i64,) = sqlx::query_as(query)
+        .bind(user_id)
+        .bind(project::DEFAULT_PROJECT_NAME)
+        .bind(data.creation_time)
+        .fetch_one(&mut tx)
+        .await?;
+
+    insert_push_key(
+        &mut tx,
+        user_id,
+        row.0,
+        &data.project_id,
+        data.creation_time,
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(user_id)
+}
+
+/// Saves a new project along with its first push key.
+pub async fn insert_project(
+    pool: &Pool<Postgres>,
+    data: &Project,
+    project_id: &str,
+) -> Result<i64> {
+    let mut tx = pool.begin().await?;
+
+    let query = r#"INSERT INTO "project"("user_id", "name", "transport_types", "creation_time") VALUES($1, $2, $3, $4) RETURNING "id""#;
+    let row: (i64,) = sqlx::query_as(query)
+        .bind(data.user_id)
+        .bind(&data.name)
+        .bind(&data.transport_types)
+        .bind(data.creation_time)
+        .fetch_one(&mut tx)
+        .await?;
+
+    insert_push_key(&mut tx, data.user_id, row.0, project_id, data.creation_time).await?;
+
+    tx.commit().await?;
+
+    Ok(row.0)
+}
+
+/// Deletes a project and revokes its push keys, messages pushed to it are kept.
+pub async fn delete_project(pool: &Pool<Postgres>, id: i64) -> Result<()> {
+    let mut tx = pool.begin().await?;
+
+    let query = r#"DELETE FROM "push_key" WHERE "project" = $1"#;
+    sqlx::query(query).bind(id).execute(&mut tx).await?;
+
+    let query = r#"DELETE FROM "project" WHERE "id" = $1"#;
+    sqlx::query(query).bind(id).execute(&mut tx).await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Makes `project_id` the current push key of the project. Keys issued before
+/// stay valid for `grace_period` seconds.
+pub async fn rotate_project_id(
+    pool: &Pool<Postgres>,
+    project: &Project,
+    project_id: &str,
+    grace_period: i64,
+) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    let now = chrono::Utc::now();
+
+    // The user keeps referring to the current key of its default project
+    let query = r#"UPDATE "user" SET "project_id" = $2 WHERE "id" = $1 AND "project_id" IN (SELECT "key" FROM "push_key" WHERE "project" = $3)"#;
+    sqlx::query(query)
+        .bind(project.user_id)
+        .bind(project_id)
+        .bind(project.id)
+        .execute(&mut tx)
+        .await?;
+
+    let query = r#"UPDATE "push_key" SET "expire_time" = LEAST("expire_time", $3) WHERE "project" = $1 AND ("expire_time" IS NULL OR "expire_time" > $2)"#;
+    sqlx::query(query)
+        .bind(project.id)
+        .bind(now)
+        .bind(now + chrono::Duration::seconds(grace_period))
+        .execute(&mut tx)
+        .await?;
+
+    insert_push_key(&mut tx, project.user_id, project.id, project_id, now).await?;
+
     tx.commit().await?;
 
-    Ok(ids)
+    Ok(())
 }
@@ -3,6 +3,12 @@ use sqlx::{Pool, Postgres};
 
 pub mod transport_type {
     pub const TELEGRAM: &str = "telegram";
+    pub const EMAIL: &str = "email";
+    pub const WEBHOOK: &str = "webhook";
+    pub const SLACK: &str = "slack";
+    pub const DISCORD: &str = "discord";
+
+    pub const ALL: &[&str] = &[TELEGRAM, EMAIL, WEBHOOK, SLACK, DISCORD];
 }
 
 #[derive(sqlx::FromRow)]
@@ -14,6 +20,7 @@ pub struct Transport {
     pub transport_type: String,
     pub chat_id: Option<String>,
     pub username: Option<String>,
+    pub secret: Option<String>,
     pub connected: bool,
     pub creation_time: chrono::DateTime<chrono::Utc>,
 }
@@ -26,6 +33,7 @@ impl Transport {
             transport_type: String::from(transport_type),
             chat_id: None,
             username: None,
+            secret: None,
             connected: false,
             creation_time: chrono::Utc::now(),
         }
@@ -42,14 +50,15 @@ impl TransportModel {
     }
 
     pub async fn insert(&self, data: &Transport) -> Result<i64> {
-        let query = r#"INSERT INTO "transport"("user_id", "type", "chat_id", "username", "connected", "creation_time") VALUES($1, $2, $3, $4, $5, $6) RETURNING "id""#;
+        let query = r#"INSERT INTO "transport"("user_id", "type", "chat_id", "username", "secret", "connected", "creation_time") VALUES($1, $2, $3, $4, $5, $6, $7) RETURNING "id""#;
         let row: (i64,) = sqlx::query_as(query)
             .bind(data.user_id)
             .bind(&data.transport_type)
             .bind(&data.chat_id)
             .bind(&data.username)
-            .bind(&data.connected)
-            .bind(&data.creation_time)
+            .bind(&data.secret)
+            .bind(data.connected)
+            .bind(data.creation_time)
             .fetch_one(&self.pool)
             .await?;
         Ok(row.0)
@@ -57,10 +66,7 @@ impl TransportModel {
 
     pub async fn find_one_by_id(&self, id: i64) -> Result<Transport> {
         let query = r#"SELECT * FROM "transport" WHERE "id" = $1"#;
-        let transport = sqlx::query_as(query)
-            .bind(id)
-            .fetch_one(&self.pool)
-            .await?;
+        let transport = sqlx::query_as(query).bind(id).fetch_one(&self.pool).await?;
         Ok(transport)
     }
 
@@ -73,6 +79,15 @@ impl TransportModel {
         Ok(transports)
     }
 
+    pub async fn find_all_connected_by_user_id(&self, user_id: i64) -> Result<Vec<Transport>> {
+        let query = r#"SELECT * FROM "transport" WHERE "user_id" = $1 AND "connected" AND "chat_id" IS NOT NULL"#;
+        let transports = sqlx::query_as(query)
+            .bind(user_id)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(transports)
+    }
+
     pub async fn find_one_by_user_id_type(
         &self,
         user_id: i64,
@@ -104,4 +119,71 @@ impl TransportModel {
             .await?;
         Ok(())
     }
+
+    /// Moves the transports of a chat to `new_chat_id`.
+    pub async fn update_chat_id_by_chat_id(
+        &self,
+        transport_type: &str,
+        chat_id: &str,
+        new_chat_id: &str,
+    ) -> Result<()> {
+        let query = r#"UPDATE "transport" SET "chat_id" = $1 WHERE "type" = $2 AND "chat_id" = $3"#;
+        sqlx::query(query)
+            .bind(new_chat_id)
+            .bind(transport_type)
+            .bind(chat_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn update_connected_by_chat_id(
+        &self,
+        transport_type: &str,
+        chat_id: &str,
+        connected: bool,
+    ) -> Result<()> {
+        let query =
+            r#"UPDATE "transport" SET "connected" = $1 WHERE "type" = $2 AND "chat_id" = $3"#;
+        sqlx::query(query)
+            .bind(connected)
+            .bind(transport_type)
+            .bind(chat_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn update_connected(
+        &self,
+        user_id: i64,
+        transport_type: &str,
+        connected: bool,
+    ) -> Result<()> {
+        let query =
+            r#"UPDATE "transport" SET "connected" = $1 WHERE "user_id" = $2 AND "type" = $3"#;
+        sqlx::query(query)
+            .bind(connected)
+            .bind(user_id)
+            .bind(transport_type)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn update_secret(
+        &self,
+        user_id: i64,
+        transport_type: &str,
+        secret: &str,
+    ) -> Result<()> {
+        let query = r#"UPDATE "transport" SET "secret" = $1 WHERE "user_id" = $2 AND "type" = $3"#;
+        sqlx::query(query)
+            .bind(secret)
+            .bind(user_id)
+            .bind(transport_type)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
 }
@@ -40,18 +40,6 @@ impl UserModel {
         UserModel { pool }
     }
 
-    pub async fn insert(&self, data: &User) -> Result<i64> {
-        let query = r#"INSERT INTO "user"("open_id", "project_id", "wallet_address", "creation_time") VALUES($1, $2, $3, $4) RETURNING "id""#;
-        let row: (i64,) = sqlx::query_as(query)
-            .bind(data.open_id)
-            .bind(&data.project_id)
-            .bind(&data.wallet_address)
-            .bind(&data.creation_time)
-            .fetch_one(&self.pool)
-            .await?;
-        Ok(row.0)
-    }
-
     pub async fn find_one_by_open_id(&self, open_id: &str) -> Result<User> {
         let open_id = uuid::Uuid::from_str(open_id)?;
         let query = r#"SELECT * FROM "user" WHERE "open_id" = $1"#;
@@ -62,15 +50,6 @@ impl UserModel {
         Ok(user)
     }
 
-    pub async fn find_one_by_project_id(&self, project_id: &str) -> Result<User> {
-        let query = r#"SELECT * FROM "user" WHERE "project_id" = $1"#;
-        let user = sqlx::query_as(query)
-            .bind(project_id)
-            .fetch_one(&self.pool)
-            .await?;
-        Ok(user)
-    }
-
     pub async fn find_one_by_wallet_address(&self, wallet_address: &str) -> Result<User> {
         let query = r#"SELECT * FROM "user" WHERE "wallet_address" = $1"#;
         let user = sqlx::query_as(query)
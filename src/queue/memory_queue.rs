@@ -0,0 +1,249 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum EntryState {
+    Queued,
+    Claimed,
+    Dead,
+}
+
+struct Entry {
+    state: EntryState,
+    /// Due time of queued tasks, claim expiry of claimed tasks, burial time of dead tasks
+    score: i64,
+}
+
+/// Queue kept in process memory. Nothing survives a restart, so it only suits
+/// single instance deployments and tests.
+pub struct MemoryQueue {
+    entries: Mutex<HashMap<i64, Entry>>,
+    visibility_timeout: i64,
+}
+
+impl MemoryQueue {
+    pub fn new(visibility_timeout: i64) -> Self {
+        MemoryQueue {
+            entries: Mutex::new(HashMap::new()),
+            visibility_timeout,
+        }
+    }
+
+    fn set(&self, task_id: i64, state: EntryState, score: i64) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(task_id, Entry { state, score });
+    }
+}
+
+#[async_trait]
+impl super::Queue for MemoryQueue {
+    async fn enqueue(&self, items: &[(i64, i64)]) -> Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        for (task_id, at) in items {
+            entries.insert(
+                *task_id,
+                Entry {
+                    state: EntryState::Queued,
+                    score: *at,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    async fn claim_due(&self, now: i64, limit: usize) -> Result<Vec<i64>> {
+        let mut entries = self.entries.lock().unwrap();
+
+        let mut due = entries
+            .iter()
+            .filter(|(_, entry)| entry.state != EntryState::Dead && entry.score <= now)
+            .map(|(task_id, entry)| (entry.score, *task_id))
+            .collect::<Vec<(i64, i64)>>();
+        due.sort_unstable();
+        due.truncate(limit);
+
+        let deadline = now + self.visibility_timeout;
+        let task_ids = due
+            .into_iter()
+            .map(|(_, task_id)| task_id)
+            .collect::<Vec<_>>();
+        for task_id in &task_ids {
+            entries.insert(
+                *task_id,
+                Entry {
+                    state: EntryState::Claimed,
+                    score: deadline,
+                },
+            );
+        }
+
+        Ok(task_ids)
+    }
+
+    async fn extend(&self, task_id: i64, now: i64) -> Result<bool> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get_mut(&task_id) {
+            Some(entry) if entry.state == EntryState::Claimed && entry.score > now => {
+                entry.score = now + self.visibility_timeout;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn ack(&self, task_id: i64) -> Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(Entry {
+            state: EntryState::Claimed,
+            ..
+        }) = entries.get(&task_id)
+        {
+            entries.remove(&task_id);
+        }
+        Ok(())
+    }
+
+    async fn nack(&self, task_id: i64, at: i64) -> Result<()> {
+        self.set(task_id, EntryState::Queued, at);
+        Ok(())
+    }
+
+    async fn recover(&self, task_id: i64, at: i64) -> Result<bool> {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.contains_key(&task_id) {
+            return Ok(false);
+        }
+
+        entries.insert(
+            task_id,
+            Entry {
+                state: EntryState::Queued,
+                score: at,
+            },
+        );
+        Ok(true)
+    }
+
+    async fn bury(&self, task_id: i64, now: i64) -> Result<()> {
+        self.set(task_id, EntryState::Dead, now);
+        Ok(())
+    }
+
+    async fn buried(&self) -> Result<Vec<(i64, i64)>> {
+        let entries = self.entries.lock().unwrap();
+        let mut items = entries
+            .iter()
+            .filter(|(_, entry)| entry.state == EntryState::Dead)
+            .map(|(task_id, entry)| (*task_id, entry.score))
+            .collect::<Vec<(i64, i64)>>();
+        items.sort_unstable_by_key(|(_, score)| *score);
+        Ok(items)
+    }
+
+    async fn unbury(&self, task_id: i64, at: i64) -> Result<()> {
+        self.set(task_id, EntryState::Queued, at);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::Queue;
+
+    #[async_std::test]
+    async fn claims_due_tasks_in_order() {
+        let queue = MemoryQueue::new(300);
+        queue.enqueue(&[(1, 20), (2, 10), (3, 100)]).await.unwrap();
+
+        assert_eq!(queue.claim_due(50, 10).await.unwrap(), vec![2, 1]);
+        assert!(queue.claim_due(50, 10).await.unwrap().is_empty());
+        assert_eq!(queue.claim_due(100, 10).await.unwrap(), vec![3]);
+    }
+
+    #[async_std::test]
+    async fn claims_at_most_limit_tasks() {
+        let queue = MemoryQueue::new(300);
+        queue.enqueue(&[(1, 1), (2, 2), (3, 3)]).await.unwrap();
+
+        assert_eq!(queue.claim_due(10, 2).await.unwrap(), vec![1, 2]);
+        assert_eq!(queue.claim_due(10, 2).await.unwrap(), vec![3]);
+    }
+
+    #[async_std::test]
+    async fn hands_out_claimed_task_again_after_visibility_timeout() {
+        let queue = MemoryQueue::new(300);
+        queue.enqueue(&[(1, 0)]).await.unwrap();
+
+        assert_eq!(queue.claim_due(0, 10).await.unwrap(), vec![1]);
+        assert!(queue.claim_due(299, 10).await.unwrap().is_empty());
+        assert_eq!(queue.claim_due(300, 10).await.unwrap(), vec![1]);
+    }
+
+    #[async_std::test]
+    async fn extend_pushes_back_visibility_timeout() {
+        let queue = MemoryQueue::new(300);
+        queue.enqueue(&[(1, 0), (2, 0)]).await.unwrap();
+        queue.claim_due(0, 10).await.unwrap();
+
+        assert!(queue.extend(1, 200).await.unwrap());
+        assert_eq!(queue.claim_due(300, 10).await.unwrap(), vec![2]);
+        assert_eq!(queue.claim_due(500, 10).await.unwrap(), vec![1]);
+
+        // Expired claims are not extended
+        assert!(!queue.extend(2, 600).await.unwrap());
+        assert!(!queue.extend(3, 0).await.unwrap());
+    }
+
+    #[async_std::test]
+    async fn ack_removes_claimed_task() {
+        let queue = MemoryQueue::new(300);
+        queue.enqueue(&[(1, 0)]).await.unwrap();
+        queue.claim_due(0, 10).await.unwrap();
+        queue.ack(1).await.unwrap();
+
+        assert!(queue.claim_due(1000, 10).await.unwrap().is_empty());
+        assert!(queue.recover(1, 0).await.unwrap());
+    }
+
+    #[async_std::test]
+    async fn nack_reschedules_task() {
+        let queue = MemoryQueue::new(300);
+        queue.enqueue(&[(1, 0)]).await.unwrap();
+        queue.claim_due(0, 10).await.unwrap();
+        queue.nack(1, 60).await.unwrap();
+
+        assert!(queue.claim_due(59, 10).await.unwrap().is_empty());
+        assert_eq!(queue.claim_due(60, 10).await.unwrap(), vec![1]);
+    }
+
+    #[async_std::test]
+    async fn recover_skips_known_tasks() {
+        let queue = MemoryQueue::new(300);
+        queue.enqueue(&[(1, 0), (2, 0)]).await.unwrap();
+        queue.claim_due(0, 1).await.unwrap();
+
+        assert!(!queue.recover(1, 0).await.unwrap());
+        assert!(!queue.recover(2, 0).await.unwrap());
+        assert!(queue.recover(3, 0).await.unwrap());
+    }
+
+    #[async_std::test]
+    async fn buried_tasks_are_not_claimed_until_unburied() {
+        let queue = MemoryQueue::new(300);
+        queue.enqueue(&[(1, 0), (2, 0)]).await.unwrap();
+        queue.claim_due(0, 10).await.unwrap();
+        queue.bury(2, 5).await.unwrap();
+        queue.bury(1, 7).await.unwrap();
+
+        assert_eq!(queue.buried().await.unwrap(), vec![(2, 5), (1, 7)]);
+        assert!(queue.claim_due(1000, 10).await.unwrap().is_empty());
+        assert!(!queue.recover(1, 0).await.unwrap());
+
+        queue.unbury(1, 10).await.unwrap();
+        assert_eq!(queue.buried().await.unwrap(), vec![(2, 5)]);
+        assert_eq!(queue.claim_due(10, 10).await.unwrap(), vec![1]);
+    }
+}
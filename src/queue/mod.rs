@@ -0,0 +1,46 @@
+use anyhow::Result;
+use async_trait::async_trait;
+
+/// Delayed task queue shared by the push handlers and the pusher.
+///
+/// Claimed tasks are invisible to other consumers until they are acked, nacked
+/// or their visibility timeout expires, after which `claim_due` hands them out
+/// again. Tasks that ran out of retries are buried in a dead letter list.
+#[async_trait]
+pub trait Queue: Send + Sync {
+    /// Schedules `(task_id, at)` pairs for delivery at the given unix timestamps.
+    async fn enqueue(&self, items: &[(i64, i64)]) -> Result<()>;
+
+    /// Claims up to `limit` tasks that are due at `now`.
+    async fn claim_due(&self, now: i64, limit: usize) -> Result<Vec<i64>>;
+
+    /// Pushes the claim on a task back to `now` plus the visibility timeout.
+    /// Returns false if the claim has already expired.
+    async fn extend(&self, task_id: i64, now: i64) -> Result<bool>;
+
+    /// Removes a claimed task from the queue.
+    async fn ack(&self, task_id: i64) -> Result<()>;
+
+    /// Releases the claim on a task and schedules it again at `at`.
+    async fn nack(&self, task_id: i64, at: i64) -> Result<()>;
+
+    /// Schedules a task at `at` unless it is already queued, claimed or buried.
+    /// Returns whether the task was added.
+    async fn recover(&self, task_id: i64, at: i64) -> Result<bool>;
+
+    /// Moves a claimed task to the dead letter list.
+    async fn bury(&self, task_id: i64, now: i64) -> Result<()>;
+
+    /// Lists `(task_id, buried_at)` pairs of the dead letter list, oldest first.
+    async fn buried(&self) -> Result<Vec<(i64, i64)>>;
+
+    /// Moves a task from the dead letter list back to the queue.
+    async fn unbury(&self, task_id: i64, at: i64) -> Result<()>;
+}
+
+pub mod memory_queue;
+pub mod postgres_queue;
+pub mod redis_queue;
+pub use memory_queue::*;
+pub use postgres_queue::*;
+pub use redis_queue::*;
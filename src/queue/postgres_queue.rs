@@ -0,0 +1,283 @@
+use crate::model;
+use anyhow::Result;
+use async_trait::async_trait;
+use sqlx::{Pool, Postgres};
+
+mod state {
+    pub const QUEUED: &str = "queued";
+    pub const CLAIMED: &str = "claimed";
+    pub const DEAD: &str = "dead";
+}
+
+/// Queue backed by the `queue` table. Every row holds the time the task is due,
+/// or for claimed tasks the time the claim expires. Concurrent consumers skip
+/// each other's rows with `FOR UPDATE SKIP LOCKED`.
+pub struct PostgresQueue {
+    pool: Pool<Postgres>,
+    visibility_timeout: i64,
+}
+
+impl PostgresQueue {
+    pub fn new(pool: Pool<Postgres>, visibility_timeout: i64) -> Self {
+        PostgresQueue {
+            pool,
+            visibility_timeout,
+        }
+    }
+}
+
+#[async_trait]
+impl super::Queue for PostgresQueue {
+    async fn enqueue(&self, items: &[(i64, i64)]) -> Result<()> {
+        if items.is_empty() {
+            return Ok(());
+        }
+
+        let (task_ids, scores): (Vec<i64>, Vec<i64>) = items.iter().cloned().unzip();
+        let query = r#"INSERT INTO "queue"("task_id", "state", "score") SELECT "task_id", $1, "score" FROM UNNEST($2::int8[], $3::int8[]) AS t("task_id", "score") ON CONFLICT ("task_id") DO UPDATE SET "state" = EXCLUDED."state", "score" = EXCLUDED."score""#;
+        sqlx::query(query)
+            .bind(state::QUEUED)
+            .bind(task_ids)
+            .bind(scores)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn claim_due(&self, now: i64, limit: usize) -> Result<Vec<i64>> {
+        let query = r#"UPDATE "queue" SET "state" = $1, "score" = $2 WHERE "task_id" IN (SELECT "task_id" FROM "queue" WHERE "state" IN ($3, $1) AND "score" <= $4 ORDER BY "score" LIMIT $5 FOR UPDATE SKIP LOCKED) RETURNING "task_id""#;
+        let rows: Vec<(i64,)> = sqlx::query_as(query)
+            .bind(state::CLAIMED)
+            .bind(now + self.visibility_timeout)
+            .bind(state::QUEUED)
+            .bind(now)
+            .bind(limit as i64)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.into_iter().map(|row| row.0).collect())
+    }
+
+    async fn extend(&self, task_id: i64, now: i64) -> Result<bool> {
+        let query = r#"UPDATE "queue" SET "score" = $1 WHERE "task_id" = $2 AND "state" = $3 AND "score" > $4"#;
+        let result = sqlx::query(query)
+            .bind(now + self.visibility_timeout)
+            .bind(task_id)
+            .bind(state::CLAIMED)
+            .bind(now)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn ack(&self, task_id: i64) -> Result<()> {
+        let query = r#"DELETE FROM "queue" WHERE "task_id" = $1 AND "state" = $2"#;
+        sqlx::query(query)
+            .bind(task_id)
+            .bind(state::CLAIMED)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn nack(&self, task_id: i64, at: i64) -> Result<()> {
+        self.enqueue(&[(task_id, at)]).await
+    }
+
+    async fn recover(&self, task_id: i64, at: i64) -> Result<bool> {
+        // Tasks that were delivered or failed in the meantime stay out of the queue
+        let query = r#"INSERT INTO "queue"("task_id", "state", "score") SELECT "id", $2, $3 FROM "task" WHERE "id" = $1 AND "state" IN ($4, $5) ON CONFLICT ("task_id") DO NOTHING"#;
+        let result = sqlx::query(query)
+            .bind(task_id)
+            .bind(state::QUEUED)
+            .bind(at)
+            .bind(model::state::PENDING)
+            .bind(model::state::RETRYING)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn bury(&self, task_id: i64, now: i64) -> Result<()> {
+        let query = r#"INSERT INTO "queue"("task_id", "state", "score") VALUES($1, $2, $3) ON CONFLICT ("task_id") DO UPDATE SET "state" = EXCLUDED."state", "score" = EXCLUDED."score""#;
+        sqlx::query(query)
+            .bind(task_id)
+            .bind(state::DEAD)
+            .bind(now)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn buried(&self) -> Result<Vec<(i64, i64)>> {
+        let query = r#"SELECT "task_id", "score" FROM "queue" WHERE "state" = $1 ORDER BY "score""#;
+        let items = sqlx::query_as(query)
+            .bind(state::DEAD)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(items)
+    }
+
+    async fn unbury(&self, task_id: i64, at: i64) -> Result<()> {
+        self.enqueue(&[(task_id, at)]).await
+    }
+}
+
+/// Runs against the database in `SPS_TEST_POSTGRES_DSN`, every test creates its
+/// tables in a schema of its own.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::Queue;
+    use sqlx::postgres::PgPoolOptions;
+    use sqlx::Executor;
+
+    async fn test_queue(visibility_timeout: i64) -> PostgresQueue {
+        let dsn = std::env::var("SPS_TEST_POSTGRES_DSN")
+            .unwrap_or_else(|_| "postgres://postgres@localhost/sps_test".to_string());
+        let schema = format!("sps_test_{}", uuid::Uuid::new_v4().to_simple());
+
+        // A single connection, so the search path set below applies to every query
+        let pool = PgPoolOptions::new()
+            .max_connections(1)
+            .connect(&dsn)
+            .await
+            .unwrap();
+        pool.execute(format!(r#"CREATE SCHEMA "{0}"; SET search_path TO "{0}""#, schema).as_str())
+            .await
+            .unwrap();
+        pool.execute(include_str!("../../scripts/task.sql"))
+            .await
+            .unwrap();
+        pool.execute(include_str!("../../scripts/queue.sql"))
+            .await
+            .unwrap();
+
+        PostgresQueue::new(pool, visibility_timeout)
+    }
+
+    async fn insert_task(queue: &PostgresQueue, task_id: i64, state: &str) {
+        let query = r#"INSERT INTO "task"("id", "message_id", "user_id", "chat_id", "transport", "transport_type", "state", "retry_count") VALUES($1, 0, 0, '', 0, 'telegram', $2, 0)"#;
+        sqlx::query(query)
+            .bind(task_id)
+            .bind(state)
+            .execute(&queue.pool)
+            .await
+            .unwrap();
+    }
+
+    async fn claim_sorted(queue: &PostgresQueue, now: i64, limit: usize) -> Vec<i64> {
+        let mut task_ids = queue.claim_due(now, limit).await.unwrap();
+        task_ids.sort_unstable();
+        task_ids
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn claims_due_tasks() {
+        let queue = test_queue(300).await;
+        queue.enqueue(&[(1, 20), (2, 10), (3, 100)]).await.unwrap();
+
+        assert_eq!(claim_sorted(&queue, 50, 10).await, vec![1, 2]);
+        assert!(queue.claim_due(50, 10).await.unwrap().is_empty());
+        assert_eq!(queue.claim_due(100, 10).await.unwrap(), vec![3]);
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn claims_at_most_limit_tasks_oldest_first() {
+        let queue = test_queue(300).await;
+        queue.enqueue(&[(1, 1), (2, 2), (3, 3)]).await.unwrap();
+
+        assert_eq!(claim_sorted(&queue, 10, 2).await, vec![1, 2]);
+        assert_eq!(queue.claim_due(10, 2).await.unwrap(), vec![3]);
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn hands_out_claimed_task_again_after_visibility_timeout() {
+        let queue = test_queue(300).await;
+        queue.enqueue(&[(1, 0)]).await.unwrap();
+
+        assert_eq!(queue.claim_due(0, 10).await.unwrap(), vec![1]);
+        assert!(queue.claim_due(299, 10).await.unwrap().is_empty());
+        assert_eq!(queue.claim_due(300, 10).await.unwrap(), vec![1]);
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn extend_pushes_back_visibility_timeout() {
+        let queue = test_queue(300).await;
+        queue.enqueue(&[(1, 0), (2, 0)]).await.unwrap();
+        queue.claim_due(0, 10).await.unwrap();
+
+        assert!(queue.extend(1, 200).await.unwrap());
+        assert_eq!(queue.claim_due(300, 10).await.unwrap(), vec![2]);
+        assert_eq!(queue.claim_due(500, 10).await.unwrap(), vec![1]);
+
+        // Expired claims are not extended
+        assert!(!queue.extend(2, 600).await.unwrap());
+        assert!(!queue.extend(3, 0).await.unwrap());
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn ack_removes_claimed_task() {
+        let queue = test_queue(300).await;
+        insert_task(&queue, 1, model::state::PENDING).await;
+        queue.enqueue(&[(1, 0)]).await.unwrap();
+        queue.claim_due(0, 10).await.unwrap();
+        queue.ack(1).await.unwrap();
+
+        assert!(queue.claim_due(1000, 10).await.unwrap().is_empty());
+        assert!(queue.recover(1, 0).await.unwrap());
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn nack_reschedules_task() {
+        let queue = test_queue(300).await;
+        queue.enqueue(&[(1, 0)]).await.unwrap();
+        queue.claim_due(0, 10).await.unwrap();
+        queue.nack(1, 60).await.unwrap();
+
+        assert!(queue.claim_due(59, 10).await.unwrap().is_empty());
+        assert_eq!(queue.claim_due(60, 10).await.unwrap(), vec![1]);
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn recover_skips_known_and_finished_tasks() {
+        let queue = test_queue(300).await;
+        insert_task(&queue, 1, model::state::PENDING).await;
+        insert_task(&queue, 2, model::state::PENDING).await;
+        insert_task(&queue, 3, model::state::RETRYING).await;
+        insert_task(&queue, 4, model::state::DONE).await;
+        queue.enqueue(&[(1, 0), (2, 0)]).await.unwrap();
+        queue.claim_due(0, 1).await.unwrap();
+
+        assert!(!queue.recover(1, 0).await.unwrap());
+        assert!(!queue.recover(2, 0).await.unwrap());
+        assert!(queue.recover(3, 0).await.unwrap());
+        assert!(!queue.recover(4, 0).await.unwrap());
+        assert!(!queue.recover(5, 0).await.unwrap());
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn buried_tasks_are_not_claimed_until_unburied() {
+        let queue = test_queue(300).await;
+        insert_task(&queue, 1, model::state::PENDING).await;
+        queue.enqueue(&[(1, 0), (2, 0)]).await.unwrap();
+        queue.claim_due(0, 10).await.unwrap();
+        queue.bury(2, 5).await.unwrap();
+        queue.bury(1, 7).await.unwrap();
+
+        assert_eq!(queue.buried().await.unwrap(), vec![(2, 5), (1, 7)]);
+        assert!(queue.claim_due(1000, 10).await.unwrap().is_empty());
+        assert!(!queue.recover(1, 0).await.unwrap());
+
+        queue.unbury(1, 10).await.unwrap();
+        assert_eq!(queue.buried().await.unwrap(), vec![(2, 5)]);
+        assert_eq!(queue.claim_due(10, 10).await.unwrap(), vec![1]);
+    }
+}
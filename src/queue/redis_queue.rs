@@ -0,0 +1,309 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use redis::aio::MultiplexedConnection;
+use redis::AsyncCommands;
+
+/// Returns tasks whose claim has expired to the queue, then atomically moves
+/// due tasks to the in-flight set, which is scored by the time the claim expires.
+const CLAIM_SCRIPT: &str = r#"
+local expired = redis.call('ZRANGEBYSCORE', KEYS[2], '-inf', ARGV[1], 'LIMIT', 0, ARGV[2])
+for _, id in ipairs(expired) do
+    redis.call('ZREM', KEYS[2], id)
+    redis.call('ZADD', KEYS[1], ARGV[1], id)
+end
+local ids = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[1], 'LIMIT', 0, ARGV[2])
+for _, id in ipairs(ids) do
+    redis.call('ZREM', KEYS[1], id)
+    redis.call('ZADD', KEYS[2], ARGV[3], id)
+end
+return ids
+"#;
+
+/// Pushes back the expiry of a claim that has not expired yet.
+const EXTEND_SCRIPT: &str = r#"
+local expiry = redis.call('ZSCORE', KEYS[1], ARGV[1])
+if not expiry or tonumber(expiry) <= tonumber(ARGV[2]) then
+    return 0
+end
+redis.call('ZADD', KEYS[1], ARGV[3], ARGV[1])
+return 1
+"#;
+
+/// Adds the task to the queue unless it is already queued, in flight or buried.
+const RECOVER_SCRIPT: &str = r#"
+if redis.call('ZSCORE', KEYS[1], ARGV[1]) or redis.call('ZSCORE', KEYS[2], ARGV[1]) or redis.call('ZSCORE', KEYS[3], ARGV[1]) then
+    return 0
+end
+redis.call('ZADD', KEYS[1], ARGV[2], ARGV[1])
+return 1
+"#;
+
+/// Queue backed by redis sorted sets scored by unix timestamps.
+pub struct RedisQueue {
+    conn: MultiplexedConnection,
+    queue_name: String,
+    inflight_queue_name: String,
+    dead_letter_queue_name: String,
+    visibility_timeout: i64,
+    claim_script: redis::Script,
+    extend_script: redis::Script,
+    recover_script: redis::Script,
+}
+
+impl RedisQueue {
+    pub async fn new(
+        client: &redis::Client,
+        conf: &crate::config::Redis,
+        visibility_timeout: i64,
+    ) -> Result<Self> {
+        let conn = client.get_multiplexed_async_std_connection().await?;
+
+        Ok(RedisQueue {
+            conn,
+            queue_name: conf.queue_name.clone(),
+            inflight_queue_name: conf.inflight_queue_name.clone(),
+            dead_letter_queue_name: conf.dead_letter_queue_name.clone(),
+            visibility_timeout,
+            claim_script: redis::Script::new(CLAIM_SCRIPT),
+            extend_script: redis::Script::new(EXTEND_SCRIPT),
+            recover_script: redis::Script::new(RECOVER_SCRIPT),
+        })
+    }
+}
+
+#[async_trait]
+impl super::Queue for RedisQueue {
+    async fn enqueue(&self, items: &[(i64, i64)]) -> Result<()> {
+        if items.is_empty() {
+            return Ok(());
+        }
+
+        let items = items
+            .iter()
+            .map(|(task_id, at)| (*at, *task_id))
+            .collect::<Vec<(i64, i64)>>();
+        self.conn
+            .clone()
+            .zadd_multiple::<_, _, _, ()>(self.queue_name.as_str(), items.as_slice())
+            .await?;
+        Ok(())
+    }
+
+    async fn claim_due(&self, now: i64, limit: usize) -> Result<Vec<i64>> {
+        let task_ids = self
+            .claim_script
+            .key(self.queue_name.as_str())
+            .key(self.inflight_queue_name.as_str())
+            .arg(now)
+            .arg(limit)
+            .arg(now + self.visibility_timeout)
+            .invoke_async(&mut self.conn.clone())
+            .await?;
+        Ok(task_ids)
+    }
+
+    async fn extend(&self, task_id: i64, now: i64) -> Result<bool> {
+        let extended: i64 = self
+            .extend_script
+            .key(self.inflight_queue_name.as_str())
+            .arg(task_id)
+            .arg(now)
+            .arg(now + self.visibility_timeout)
+            .invoke_async(&mut self.conn.clone())
+            .await?;
+        Ok(extended > 0)
+    }
+
+    async fn ack(&self, task_id: i64) -> Result<()> {
+        self.conn
+            .clone()
+            .zrem::<_, _, ()>(self.inflight_queue_name.as_str(), task_id)
+            .await?;
+        Ok(())
+    }
+
+    async fn nack(&self, task_id: i64, at: i64) -> Result<()> {
+        redis::pipe()
+            .atomic()
+            .zadd(self.queue_name.as_str(), task_id, at)
+            .ignore()
+            .zrem(self.inflight_queue_name.as_str(), task_id)
+            .ignore()
+            .query_async::<_, ()>(&mut self.conn.clone())
+            .await?;
+        Ok(())
+    }
+
+    async fn recover(&self, task_id: i64, at: i64) -> Result<bool> {
+        let added: i64 = self
+            .recover_script
+            .key(self.queue_name.as_str())
+            .key(self.inflight_queue_name.as_str())
+            .key(self.dead_letter_queue_name.as_str())
+            .arg(task_id)
+            .arg(at)
+            .invoke_async(&mut self.conn.clone())
+            .await?;
+        Ok(added > 0)
+    }
+
+    async fn bury(&self, task_id: i64, now: i64) -> Result<()> {
+        redis::pipe()
+            .atomic()
+            .zadd(self.dead_letter_queue_name.as_str(), task_id, now)
+            .ignore()
+            .zrem(self.inflight_queue_name.as_str(), task_id)
+            .ignore()
+            .query_async::<_, ()>(&mut self.conn.clone())
+            .await?;
+        Ok(())
+    }
+
+    async fn buried(&self) -> Result<Vec<(i64, i64)>> {
+        let items = self
+            .conn
+            .clone()
+            .zrange_withscores(self.dead_letter_queue_name.as_str(), 0, -1)
+            .await?;
+        Ok(items)
+    }
+
+    async fn unbury(&self, task_id: i64, at: i64) -> Result<()> {
+        redis::pipe()
+            .atomic()
+            .zrem(self.dead_letter_queue_name.as_str(), task_id)
+            .ignore()
+            .zadd(self.queue_name.as_str(), task_id, at)
+            .ignore()
+            .query_async::<_, ()>(&mut self.conn.clone())
+            .await?;
+        Ok(())
+    }
+}
+
+/// Runs against the server in `SPS_TEST_REDIS_URL`, every test uses keys of its
+/// own.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::Queue;
+
+    async fn test_queue(visibility_timeout: i64) -> RedisQueue {
+        let url = std::env::var("SPS_TEST_REDIS_URL")
+            .unwrap_or_else(|_| "redis://localhost:6379/0".to_string());
+        let prefix = format!("sps_test_{}", uuid::Uuid::new_v4().to_simple());
+        let conf = crate::config::Redis {
+            url: url.clone(),
+            queue_name: format!("{}_queue", prefix),
+            inflight_queue_name: format!("{}_inflight", prefix),
+            dead_letter_queue_name: format!("{}_dead_letter", prefix),
+        };
+
+        let client = redis::Client::open(url.as_str()).unwrap();
+        RedisQueue::new(&client, &conf, visibility_timeout)
+            .await
+            .unwrap()
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn claims_due_tasks_in_order() {
+        let queue = test_queue(300).await;
+        queue.enqueue(&[(1, 20), (2, 10), (3, 100)]).await.unwrap();
+
+        assert_eq!(queue.claim_due(50, 10).await.unwrap(), vec![2, 1]);
+        assert!(queue.claim_due(50, 10).await.unwrap().is_empty());
+        assert_eq!(queue.claim_due(100, 10).await.unwrap(), vec![3]);
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn claims_at_most_limit_tasks() {
+        let queue = test_queue(300).await;
+        queue.enqueue(&[(1, 1), (2, 2), (3, 3)]).await.unwrap();
+
+        assert_eq!(queue.claim_due(10, 2).await.unwrap(), vec![1, 2]);
+        assert_eq!(queue.claim_due(10, 2).await.unwrap(), vec![3]);
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn hands_out_claimed_task_again_after_visibility_timeout() {
+        let queue = test_queue(300).await;
+        queue.enqueue(&[(1, 0)]).await.unwrap();
+
+        assert_eq!(queue.claim_due(0, 10).await.unwrap(), vec![1]);
+        assert!(queue.claim_due(299, 10).await.unwrap().is_empty());
+        assert_eq!(queue.claim_due(300, 10).await.unwrap(), vec![1]);
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn extend_pushes_back_visibility_timeout() {
+        let queue = test_queue(300).await;
+        queue.enqueue(&[(1, 0), (2, 0)]).await.unwrap();
+        queue.claim_due(0, 10).await.unwrap();
+
+        assert!(queue.extend(1, 200).await.unwrap());
+        assert_eq!(queue.claim_due(300, 10).await.unwrap(), vec![2]);
+        assert_eq!(queue.claim_due(500, 10).await.unwrap(), vec![1]);
+
+        // Expired claims are not extended
+        assert!(!queue.extend(2, 600).await.unwrap());
+        assert!(!queue.extend(3, 0).await.unwrap());
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn ack_removes_claimed_task() {
+        let queue = test_queue(300).await;
+        queue.enqueue(&[(1, 0)]).await.unwrap();
+        queue.claim_due(0, 10).await.unwrap();
+        queue.ack(1).await.unwrap();
+
+        assert!(queue.claim_due(1000, 10).await.unwrap().is_empty());
+        assert!(queue.recover(1, 0).await.unwrap());
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn nack_reschedules_task() {
+        let queue = test_queue(300).await;
+        queue.enqueue(&[(1, 0)]).await.unwrap();
+        queue.claim_due(0, 10).await.unwrap();
+        queue.nack(1, 60).await.unwrap();
+
+        assert!(queue.claim_due(59, 10).await.unwrap().is_empty());
+        assert_eq!(queue.claim_due(60, 10).await.unwrap(), vec![1]);
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn recover_skips_known_tasks() {
+        let queue = test_queue(300).await;
+        queue.enqueue(&[(1, 0), (2, 0)]).await.unwrap();
+        queue.claim_due(0, 1).await.unwrap();
+
+        assert!(!queue.recover(1, 0).await.unwrap());
+        assert!(!queue.recover(2, 0).await.unwrap());
+        assert!(queue.recover(3, 0).await.unwrap());
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn buried_tasks_are_not_claimed_until_unburied() {
+        let queue = test_queue(300).await;
+        queue.enqueue(&[(1, 0), (2, 0)]).await.unwrap();
+        queue.claim_due(0, 10).await.unwrap();
+        queue.bury(2, 5).await.unwrap();
+        queue.bury(1, 7).await.unwrap();
+
+        assert_eq!(queue.buried().await.unwrap(), vec![(2, 5), (1, 7)]);
+        assert!(queue.claim_due(1000, 10).await.unwrap().is_empty());
+        assert!(!queue.recover(1, 0).await.unwrap());
+
+        queue.unbury(1, 10).await.unwrap();
+        assert_eq!(queue.buried().await.unwrap(), vec![(2, 5)]);
+        assert_eq!(queue.claim_due(10, 10).await.unwrap(), vec![1]);
+    }
+}
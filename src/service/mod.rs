@@ -1,16 +1,67 @@
 use crate::config::Config;
+use crate::config::QueueBackend;
+use crate::logic::eip1271;
 use crate::model;
+use crate::queue::{self, Queue};
 use anyhow::Result;
-use async_std::sync::{Arc, Mutex};
+use async_std::sync::{Arc, Mutex, MutexGuard};
 use sqlx::postgres::PgPoolOptions;
 use sqlx::{Pool, Postgres};
+use std::ops::{Deref, DerefMut};
+
+/// Redis connection that is opened on first use, so processes that never touch
+/// redis, e.g. the dead letter commands with the postgres queue backend, run
+/// without it.
+pub struct RedisConnection {
+    client: redis::Client,
+    connection: Mutex<Option<redis::aio::Connection>>,
+}
+
+impl RedisConnection {
+    fn new(client: redis::Client) -> Self {
+        RedisConnection {
+            client,
+            connection: Mutex::new(None),
+        }
+    }
+
+    pub async fn lock(&self) -> Result<RedisGuard<'_>> {
+        let mut guard = self.connection.lock().await;
+        if guard.is_none() {
+            *guard = Some(self.client.get_async_std_connection().await?);
+        }
+
+        Ok(RedisGuard(guard))
+    }
+}
+
+pub struct RedisGuard<'a>(MutexGuard<'a, Option<redis::aio::Connection>>);
+
+impl Deref for RedisGuard<'_> {
+    type Target = redis::aio::Connection;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref().expect("connected in RedisConnection::lock")
+    }
+}
+
+impl DerefMut for RedisGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.0.as_mut().expect("connected in RedisConnection::lock")
+    }
+}
 
 pub struct Context {
     pub conf: Config,
     pub pool: Pool<Postgres>,
     pub redis_client: redis::Client,
-    pub redis_connection: Mutex<redis::aio::Connection>,
+    pub redis_connection: RedisConnection,
+    pub queue: Box<dyn Queue>,
+    /// Set when smart contract wallets can sign in, see `auth.rpc_url`
+    pub eip1271: Option<eip1271::Verifier>,
     pub message_model: model::MessageModel,
+    pub project_model: model::ProjectModel,
+    pub push_key_model: model::PushKeyModel,
     pub task_model: model::TaskModel,
     pub transport_model: model::TransportModel,
     pub user_model: model::UserModel,
@@ -23,15 +74,34 @@ impl Context {
             .connect(&c.postgres.dsn)
             .await?;
 
+        // Only parses the url, connections are opened by the users of redis
         let redis_client = redis::Client::open(c.redis.url.as_str())?;
-        let redis_connection = redis_client.get_async_std_connection().await?;
+
+        let visibility_timeout = c.pusher.visibility_timeout;
+        let queue: Box<dyn Queue> = match c.queue.backend {
+            QueueBackend::Redis => {
+                Box::new(queue::RedisQueue::new(&redis_client, &c.redis, visibility_timeout).await?)
+            }
+            QueueBackend::Postgres => {
+                Box::new(queue::PostgresQueue::new(pool.clone(), visibility_timeout))
+            }
+            QueueBackend::Memory => Box::new(queue::MemoryQueue::new(visibility_timeout)),
+        };
 
         let ctx = Context {
             conf: c.clone(),
             pool: pool.clone(),
+            redis_connection: RedisConnection::new(redis_client.clone()),
             redis_client,
-            redis_connection: Mutex::new(redis_connection),
+            queue,
+            eip1271: c
+                .auth
+                .rpc_url
+                .as_ref()
+                .map(|rpc_url| eip1271::Verifier::new(rpc_url, c.auth.chain_id)),
             message_model: model::MessageModel::new(pool.clone()),
+            project_model: model::ProjectModel::new(pool.clone()),
+            push_key_model: model::PushKeyModel::new(pool.clone()),
             task_model: model::TaskModel::new(pool.clone()),
             transport_model: model::TransportModel::new(pool.clone()),
             user_model: model::UserModel::new(pool.clone()),
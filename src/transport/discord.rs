@@ -0,0 +1,118 @@
+use super::DeliveryError;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::time::Duration;
+
+const MAX_CONTENT_LENGTH: usize = 2000;
+const MAX_EMBED_TITLE_LENGTH: usize = 256;
+const MAX_EMBED_DESCRIPTION_LENGTH: usize = 4096;
+/// Statuses for invalid tokens and unknown webhooks.
+const PERMANENT_STATUSES: [u16; 3] = [401, 403, 404];
+
+pub struct Discord {
+    client: surf::Client,
+}
+
+impl Discord {
+    pub fn new(timeout: u64) -> Result<Self> {
+        let client = surf::Config::new()
+            .set_timeout(Some(Duration::from_secs(timeout)))
+            .try_into()?;
+
+        Ok(Discord { client })
+    }
+}
+
+#[derive(Serialize)]
+struct Embed {
+    title: String,
+    description: String,
+}
+
+#[derive(Serialize)]
+struct ExecuteWebhook {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    embeds: Vec<Embed>,
+}
+
+#[derive(Deserialize)]
+struct RateLimitResponse {
+    retry_after: f64,
+}
+
+#[async_trait]
+impl super::Transport for Discord {
+    fn max_length(&self, title: &str) -> Option<usize> {
+        match title.is_empty() {
+            true => Some(MAX_CONTENT_LENGTH),
+            false => Some(MAX_EMBED_DESCRIPTION_LENGTH),
+        }
+    }
+
+    async fn push(&self, chat: &str, title: &str, message: &str) -> Result<()> {
+        let data = &if title.is_empty() {
+            ExecuteWebhook {
+                content: Some(super::truncate(message, MAX_CONTENT_LENGTH)),
+                embeds: Vec::new(),
+            }
+        } else {
+            ExecuteWebhook {
+                content: None,
+                embeds: vec![Embed {
+                    title: super::truncate(title, MAX_EMBED_TITLE_LENGTH),
+                    description: super::truncate(message, MAX_EMBED_DESCRIPTION_LENGTH),
+                }],
+            }
+        };
+
+        let mut retried = false;
+        loop {
+            let mut res = match self.client.post(chat).body_json(data) {
+                Ok(req) => match req.await {
+                    Ok(res) => res,
+                    Err(err) => return Err(err.into_inner()),
+                },
+                Err(err) => return Err(err.into_inner()),
+            };
+
+            if res.status().is_success() {
+                return Ok(());
+            }
+
+            // Discord answers 429 with the delay in seconds in the `retry_after` field
+            if res.status() == 429 {
+                let retry_after = match res.body_json::<RateLimitResponse>().await {
+                    Ok(payload) => super::retry_after_secs(payload.retry_after),
+                    Err(_) => 1.0,
+                };
+                if !retried && retry_after <= super::MAX_INLINE_RETRY_AFTER {
+                    retried = true;
+                    async_std::task::sleep(Duration::from_secs_f64(retry_after)).await;
+                    continue;
+                }
+
+                return Err(DeliveryError::rate_limited("rate limited", retry_after));
+            }
+
+            let status = res.status();
+            let description = res.body_string().await.unwrap_or_default();
+            // The webhook was revoked or its channel removed
+            if PERMANENT_STATUSES.contains(&(status as u16)) {
+                return Err(DeliveryError::permanent(format!(
+                    "discord responded with status {}, {}",
+                    status, description
+                )));
+            }
+
+            return Err(anyhow!(
+                "discord responded with status {}, {}",
+                status,
+                description
+            ));
+        }
+    }
+}
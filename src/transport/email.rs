@@ -0,0 +1,101 @@
+use super::DeliveryError;
+use crate::config::{self, SmtpTls};
+use anyhow::Result;
+use async_trait::async_trait;
+use lettre::message::{Mailbox, MultiPart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{SmtpTransport, Transport as _};
+use std::time::Duration;
+
+const DEFAULT_SUBJECT: &str = "Notification from simple push service";
+
+#[derive(Clone)]
+pub struct Email {
+    from: Mailbox,
+    mailer: SmtpTransport,
+}
+
+impl Email {
+    pub fn new(conf: &config::Email) -> Result<Self> {
+        let builder = match conf.tls {
+            SmtpTls::None => SmtpTransport::builder_dangerous(&conf.host),
+            SmtpTls::Starttls => SmtpTransport::starttls_relay(&conf.host)?,
+            SmtpTls::Tls => SmtpTransport::relay(&conf.host)?,
+        };
+
+        let mut builder = builder
+            .port(conf.port)
+            .timeout(Some(Duration::from_secs(conf.timeout)));
+        if let (Some(username), Some(password)) = (&conf.username, &conf.password) {
+            if !username.is_empty() {
+                builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+            }
+        }
+
+        Ok(Email {
+            from: conf.from.parse()?,
+            mailer: builder.build(),
+        })
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            '\n' => escaped.push_str("<br>\n"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn render_html(title: &str, message: &str) -> String {
+    let mut html = String::from("<!DOCTYPE html>\n<html>\n<body>\n");
+    if !title.is_empty() {
+        html.push_str(&format!("<h3>{}</h3>\n", escape_html(title)));
+    }
+    html.push_str(&format!("<p>{}</p>\n", escape_html(message)));
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+#[async_trait]
+impl super::Transport for Email {
+    async fn push(&self, chat: &str, title: &str, message: &str) -> Result<()> {
+        let subject = if title.is_empty() {
+            DEFAULT_SUBJECT
+        } else {
+            title
+        };
+
+        let email = lettre::Message::builder()
+            .from(self.from.clone())
+            .to(chat
+                .parse::<Mailbox>()
+                .map_err(|err| DeliveryError::permanent(err.to_string()))?)
+            .subject(subject)
+            .multipart(MultiPart::alternative_plain_html(
+                message.to_string(),
+                render_html(title, message),
+            ))?;
+
+        // The SMTP client is blocking, keep it off the async executor.
+        let mailer = self.mailer.clone();
+        let result = async_std::task::spawn_blocking(move || mailer.send(&email)).await;
+        if let Err(err) = result {
+            // 5xx replies such as an unknown mailbox won't succeed on retry
+            if err.is_permanent() {
+                return Err(DeliveryError::permanent(err.to_string()));
+            }
+            return Err(err.into());
+        }
+
+        Ok(())
+    }
+}
@@ -1,10 +1,113 @@
+use crate::model;
 use anyhow::Result;
 use async_trait::async_trait;
 
 #[async_trait]
-pub trait Transport {
+pub trait Transport: Send + Sync {
     async fn push(&self, chat: &str, title: &str, message: &str) -> Result<()>;
+
+    /// Maximum number of content characters a single push with `title` can
+    /// carry, longer content is split or truncated by the pusher.
+    fn max_length(&self, _title: &str) -> Option<usize> {
+        None
+    }
+
+    /// Number of chars `c` takes up in the text that is sent, for transports
+    /// that escape the content.
+    fn char_length(&self, _c: char) -> usize {
+        1
+    }
+
+    /// Delivers a queued task. Transports that need more than the title and
+    /// content of the message can override it.
+    async fn deliver(&self, task: &model::Task, message: &model::Message) -> Result<()> {
+        self.push(&task.chat_id, &message.title, &message.content)
+            .await
+    }
+}
+
+/// Error returned by transports when a delivery fails, wrapped in an
+/// `anyhow::Error`. Errors of any other type are treated as transient.
+#[derive(Debug)]
+pub enum DeliveryError {
+    /// The delivery will never succeed, e.g. the chat is gone or the bot was blocked.
+    Permanent(String),
+    /// The delivery may succeed later and is retried with backoff.
+    Transient(String),
+    /// The transport asked to wait `retry_after` seconds before trying again.
+    RateLimited { reason: String, retry_after: u64 },
+    /// The chat moved to `chat_id`, e.g. a Telegram group that was upgraded to
+    /// a supergroup. The delivery can be retried there right away.
+    ChatMigrated { reason: String, chat_id: String },
+}
+
+impl DeliveryError {
+    pub fn permanent(reason: impl Into<String>) -> anyhow::Error {
+        DeliveryError::Permanent(reason.into()).into()
+    }
+
+    pub fn rate_limited(reason: impl Into<String>, retry_after: f64) -> anyhow::Error {
+        DeliveryError::RateLimited {
+            reason: reason.into(),
+            retry_after: retry_after.max(1.0).ceil() as u64,
+        }
+        .into()
+    }
+}
+
+impl std::fmt::Display for DeliveryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeliveryError::Permanent(reason) | DeliveryError::Transient(reason) => {
+                f.write_str(reason)
+            }
+            DeliveryError::RateLimited {
+                reason,
+                retry_after,
+            } => write!(f, "{}, retry after {} seconds", reason, retry_after),
+            DeliveryError::ChatMigrated { reason, chat_id } => {
+                write!(f, "{}, migrated to chat {}", reason, chat_id)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DeliveryError {}
+
+/// Longest time a transport sleeps before retrying a rate limited request
+/// itself, longer delays are left to the retry queue.
+pub(crate) const MAX_INLINE_RETRY_AFTER: f64 = 5.0;
+
+/// Sanitizes a delay in seconds taken from a rate limited response, negative
+/// or non-finite values fall back to one second.
+fn retry_after_secs(value: f64) -> f64 {
+    if value.is_finite() && value >= 0.0 {
+        value
+    } else {
+        1.0
+    }
+}
+
+/// Truncates `text` to at most `max_chars` characters, marking the cut with an ellipsis.
+fn truncate(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        None => text.to_string(),
+        Some(_) => {
+            let mut truncated: String = text.chars().take(max_chars.saturating_sub(1)).collect();
+            truncated.push('…');
+            truncated
+        }
+    }
 }
 
+pub mod discord;
+pub mod email;
+pub mod slack;
+pub mod split;
 pub mod telegram;
+pub mod webhook;
+pub use discord::*;
+pub use email::*;
+pub use slack::*;
 pub use telegram::*;
+pub use webhook::*;
@@ -0,0 +1,171 @@
+use super::DeliveryError;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde::Serialize;
+use std::convert::TryInto;
+use std::time::Duration;
+
+/// Slack truncates messages longer than 40,000 characters.
+const MAX_TEXT_LENGTH: usize = 40000;
+/// Statuses for invalid tokens, unknown webhooks and archived channels.
+const PERMANENT_STATUSES: [u16; 3] = [403, 404, 410];
+
+pub struct Slack {
+    client: surf::Client,
+}
+
+impl Slack {
+    pub fn new(timeout: u64) -> Result<Self> {
+        let client = surf::Config::new()
+            .set_timeout(Some(Duration::from_secs(timeout)))
+            .try_into()?;
+
+        Ok(Slack { client })
+    }
+}
+
+#[derive(Serialize)]
+struct IncomingWebhookMessage {
+    text: String,
+}
+
+/// Escapes the control characters of Slack's mrkdwn format.
+fn escape_mrkdwn(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Length of `c` after `escape_mrkdwn`.
+fn mrkdwn_len(c: char) -> usize {
+    match c {
+        '&' => "&amp;".len(),
+        '<' => "&lt;".len(),
+        '>' => "&gt;".len(),
+        _ => 1,
+    }
+}
+
+#[async_trait]
+impl super::Transport for Slack {
+    fn max_length(&self, title: &str) -> Option<usize> {
+        // The title is wrapped in `*` and followed by a blank line
+        let overhead = if title.is_empty() {
+            0
+        } else {
+            title.chars().map(mrkdwn_len).sum::<usize>() + 4
+        };
+        Some(MAX_TEXT_LENGTH.saturating_sub(overhead))
+    }
+
+    fn char_length(&self, c: char) -> usize {
+        mrkdwn_len(c)
+    }
+
+    async fn push(&self, chat: &str, title: &str, message: &str) -> Result<()> {
+        let text = if title.is_empty() {
+            escape_mrkdwn(message)
+        } else {
+            format!("*{}*\n\n{}", escape_mrkdwn(title), escape_mrkdwn(message))
+        };
+
+        let data = &IncomingWebhookMessage {
+            text: super::truncate(&text, MAX_TEXT_LENGTH),
+        };
+
+        let mut retried = false;
+        loop {
+            let mut res = match self.client.post(chat).body_json(data) {
+                Ok(req) => match req.await {
+                    Ok(res) => res,
+                    Err(err) => return Err(err.into_inner()),
+                },
+                Err(err) => return Err(err.into_inner()),
+            };
+
+            if res.status().is_success() {
+                return Ok(());
+            }
+
+            // Slack signals rate limiting with 429 and a `Retry-After` header in seconds
+            if res.status() == 429 {
+                let retry_after = res
+                    .header("Retry-After")
+                    .and_then(|value| value.as_str().parse::<f64>().ok())
+                    .map_or(1.0, super::retry_after_secs);
+                if !retried && retry_after <= super::MAX_INLINE_RETRY_AFTER {
+                    retried = true;
+                    async_std::task::sleep(Duration::from_secs_f64(retry_after)).await;
+                    continue;
+                }
+
+                return Err(DeliveryError::rate_limited("rate limited", retry_after));
+            }
+
+            let status = res.status();
+            let description = res.body_string().await.unwrap_or_default();
+            // The webhook was revoked or its channel removed
+            if PERMANENT_STATUSES.contains(&(status as u16)) {
+                return Err(DeliveryError::permanent(format!(
+                    "slack responded with status {}, {}",
+                    status, description
+                )));
+            }
+
+            return Err(anyhow!(
+                "slack responded with status {}, {}",
+                status,
+                description
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{message_format, message_overflow};
+    use crate::transport::{split, Transport};
+
+    #[test]
+    fn measures_escaped_length() {
+        let text = "a & b < c > d";
+        assert_eq!(
+            text.chars().map(mrkdwn_len).sum::<usize>(),
+            escape_mrkdwn(text).chars().count()
+        );
+    }
+
+    #[test]
+    fn fits_escaped_content_into_the_limit() {
+        let slack = Slack::new(10).unwrap();
+        let max = slack.max_length("").unwrap();
+        let char_length = |c| slack.char_length(c);
+        // Fits the limit as is, but not once escaped
+        let content = "a & b <c> ".repeat(3_000);
+        assert!(content.chars().count() < max);
+
+        let parts = split::fit_by(
+            &content,
+            message_format::PLAIN,
+            message_overflow::SPLIT,
+            max,
+            &char_length,
+        );
+        assert!(parts.len() > 1);
+        for part in &parts {
+            assert!(escape_mrkdwn(part).chars().count() <= MAX_TEXT_LENGTH);
+        }
+        assert_eq!(parts.concat(), content);
+
+        let parts = split::fit_by(
+            &content,
+            message_format::PLAIN,
+            message_overflow::TRUNCATE,
+            max,
+            &char_length,
+        );
+        assert_eq!(parts.len(), 1);
+        assert!(escape_mrkdwn(&parts[0]).chars().count() <= MAX_TEXT_LENGTH);
+    }
+}
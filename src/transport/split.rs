@@ -0,0 +1,551 @@
+use crate::model::{message_format, message_overflow};
+
+/// Appended to content that was cut off.
+pub const TRUNCATION_MARKER: &str = "…";
+
+/// Markup added to close and reopen entities at a split is bounded by this
+/// share of the maximum length, content with more nesting is split as is.
+const MAX_MARKUP_RESERVE_RATIO: usize = 2;
+
+/// Length of a char that is sent as is.
+fn unit_len(_: char) -> usize {
+    1
+}
+
+/// Length of `text` once sent, `char_len` gives the length of each char.
+fn text_len(text: &str, char_len: &dyn Fn(char) -> usize) -> usize {
+    text.chars().map(char_len).sum()
+}
+
+/// Byte index of the first char that takes the length of `text` over `budget`,
+/// or the length of `text`.
+fn byte_index(text: &str, budget: usize, char_len: &dyn Fn(char) -> usize) -> usize {
+    let mut len = 0;
+    for (index, c) in text.char_indices() {
+        len += char_len(c);
+        if len > budget {
+            return index;
+        }
+    }
+    text.len()
+}
+
+/// Finds where to cut `text` so that the head has at most `budget` chars,
+/// preferring line breaks, then whitespace. HTML tags and entities are not cut.
+fn find_cut(text: &str, budget: usize, format: &str, char_len: &dyn Fn(char) -> usize) -> usize {
+    let limit = byte_index(text, budget, char_len);
+    let window = &text[..limit];
+    let min = limit / 2;
+
+    let mut cut = match window.rfind('\n') {
+        Some(index) if index + 1 > min => index + 1,
+        _ => match window.rfind(char::is_whitespace) {
+            Some(index) if index + 1 > min => {
+                index + window[index..].chars().next().unwrap().len_utf8()
+            }
+            _ => limit,
+        },
+    };
+
+    if format == message_format::HTML {
+        let head = &text[..cut];
+        if let Some(open) = head.rfind('<') {
+            if !head[open..].contains('>') {
+                cut = open;
+            }
+        }
+        let head = &text[..cut];
+        if let Some(amp) = head.rfind('&') {
+            if !head[amp..].contains(|c: char| c == ';' || c.is_whitespace()) {
+                cut = amp;
+            }
+        }
+    }
+
+    // Always make progress. A tag or entity that exceeds the budget on its own
+    // is kept whole rather than broken.
+    if cut > 0 {
+        return cut;
+    }
+    if format == message_format::HTML {
+        let end = match text.chars().next() {
+            Some('<') => text.find('>'),
+            Some('&') => text.find(';'),
+            _ => None,
+        };
+        if let Some(end) = end {
+            return end + 1;
+        }
+    }
+    limit.max(text.chars().next().map_or(0, char::len_utf8))
+}
+
+/// Byte index in `line` of an inline code span or link that is not closed by
+/// the end of `line`.
+fn unclosed_inline(line: &str) -> Option<usize> {
+    let mut code: Option<usize> = None;
+    let mut link: Option<usize> = None;
+    let mut label_closed = false;
+    let mut url_depth: Option<usize> = None;
+    let mut escaped = false;
+    for (index, c) in line.char_indices() {
+        if code.is_some() {
+            if c == '`' {
+                code = None;
+            }
+            continue;
+        }
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        if label_closed {
+            label_closed = false;
+            if c == '(' {
+                url_depth = Some(0);
+                continue;
+            }
+            link = None;
+        }
+
+        match (c, url_depth) {
+            ('\\', _) => escaped = true,
+            ('(', Some(depth)) => url_depth = Some(depth + 1),
+            (')', Some(0)) => {
+                link = None;
+                url_depth = None;
+            }
+            (')', Some(depth)) => url_depth = Some(depth - 1),
+            (_, Some(_)) => {}
+            ('`', None) => code = Some(index),
+            ('[', None) if link.is_none() => link = Some(index),
+            (']', None) if link.is_some() => label_closed = true,
+            _ => {}
+        }
+    }
+
+    match (code, link) {
+        (Some(code), Some(link)) => Some(code.min(link)),
+        (code, link) => code.or(link),
+    }
+}
+
+/// Moves a markdown cut before an inline code span or link it would break,
+/// unless the cut is inside a code block or the span starts the text.
+fn avoid_inline_cut(prefix: &str, text: &str, cut: usize) -> usize {
+    let head = &text[..cut];
+    let (close, _) = open_markup(&format!("{}{}", prefix, head), message_format::MARKDOWN);
+    if !close.is_empty() {
+        return cut;
+    }
+
+    let line_start = head.rfind('\n').map_or(0, |index| index + 1);
+    let line = &head[line_start..];
+    if line.trim_start().starts_with("```") {
+        return cut;
+    }
+    match unclosed_inline(line) {
+        Some(index) if line_start + index > 0 => line_start + index,
+        _ => cut,
+    }
+}
+
+/// Moves an HTML cut before the first element that opens in the head and is
+/// not closed there, and past closing tags right after the cut, which cost no
+/// more than the closing tags the split would add.
+fn avoid_tag_cut(text: &str, cut: usize) -> usize {
+    let head = &text[..cut];
+    let mut stack: Vec<(usize, &str)> = Vec::new();
+    let mut pos = 0;
+    while let Some(start) = head[pos..].find('<').map(|start| pos + start) {
+        let end = match head[start..].find('>') {
+            Some(end) => start + end + 1,
+            None => break,
+        };
+        let inner = head[start + 1..end - 1].trim();
+        if let Some(name) = inner.strip_prefix('/') {
+            if let Some(index) = stack.iter().rposition(|(_, open)| *open == name.trim()) {
+                stack.truncate(index);
+            }
+        } else if !inner.ends_with('/') {
+            stack.push((start, inner.split_whitespace().next().unwrap_or("")));
+        }
+        pos = end;
+    }
+
+    let mut cut = match stack.first() {
+        Some((start, _)) if *start > 0 => *start,
+        _ => cut,
+    };
+    while text[cut..].starts_with("</") {
+        match text[cut..].find('>') {
+            Some(end) => cut += end + 1,
+            None => break,
+        }
+    }
+    cut
+}
+
+/// Markup that is still open at the end of a part: the text that closes it,
+/// and the text that reopens it at the start of the next part.
+fn open_markup(part: &str, format: &str) -> (String, String) {
+    match format {
+        message_format::MARKDOWN => {
+            let mut fence: Option<&str> = None;
+            for line in part.lines() {
+                if line.trim_start().starts_with("```") {
+                    fence = match fence {
+                        None => Some(line.trim()),
+                        Some(_) => None,
+                    };
+                }
+            }
+
+            match fence {
+                None => (String::new(), String::new()),
+                Some(opening) => {
+                    let close = if part.ends_with('\n') { "```" } else { "\n```" };
+                    (close.to_string(), format!("{}\n", opening))
+                }
+            }
+        }
+        message_format::HTML => {
+            let mut stack: Vec<(&str, &str)> = Vec::new();
+            let mut rest = part;
+            while let Some(start) = rest.find('<') {
+                let end = match rest[start..].find('>') {
+                    Some(end) => start + end + 1,
+                    None => break,
+                };
+                let tag = &rest[start..end];
+                rest = &rest[end..];
+
+                let inner = tag[1..tag.len() - 1].trim();
+                if let Some(name) = inner.strip_prefix('/') {
+                    let name = name.trim();
+                    if let Some(index) = stack.iter().rposition(|(open, _)| *open == name) {
+                        stack.truncate(index);
+                    }
+                } else if !inner.ends_with('/') {
+                    let name = inner.split_whitespace().next().unwrap_or("");
+                    stack.push((name, tag));
+                }
+            }
+
+            let close = stack
+                .iter()
+                .rev()
+                .map(|(name, _)| format!("</{}>", name))
+                .collect();
+            let reopen = stack.iter().map(|(_, tag)| *tag).collect();
+            (close, reopen)
+        }
+        _ => (String::new(), String::new()),
+    }
+}
+
+/// Splits `content` into parts of at most `budget` chars of text, plus the
+/// markup that keeps each part balanced.
+fn split_with_reserve(
+    content: &str,
+    format: &str,
+    max: usize,
+    reserve: usize,
+    char_len: &dyn Fn(char) -> usize,
+) -> Vec<String> {
+    let budget = max.saturating_sub(reserve).max(1);
+
+    let mut parts = Vec::new();
+    let mut prefix = String::new();
+    let mut rest = content;
+    loop {
+        let available = budget.saturating_sub(text_len(&prefix, char_len)).max(1);
+        if text_len(rest, char_len) <= available {
+            parts.push(format!("{}{}", prefix, rest));
+            break;
+        }
+
+        let mut cut = find_cut(rest, available, format, char_len);
+        match format {
+            message_format::MARKDOWN => cut = avoid_inline_cut(&prefix, rest, cut),
+            message_format::HTML => cut = avoid_tag_cut(rest, cut),
+            _ => {}
+        }
+        let mut part = format!("{}{}", prefix, &rest[..cut]);
+        rest = &rest[cut..];
+
+        let (close, reopen) = open_markup(&part, format);
+        part.push_str(&close);
+        parts.push(part);
+        if rest.is_empty() {
+            break;
+        }
+        prefix = reopen;
+    }
+
+    parts
+}
+
+/// Splits `content` into ordered parts of at most `max` chars. Lines and words
+/// are kept whole where possible, and markup entities are closed at the end of
+/// a part and reopened in the next one. Markdown code blocks are reopened,
+/// inline code and links are not cut unless they exceed a part on their own.
+/// Emphasis is not tracked, a marker left open by a cut renders literally.
+pub fn split(content: &str, format: &str, max: usize) -> Vec<String> {
+    split_by(content, format, max, &unit_len)
+}
+
+/// Like `split`, with the length of each char given by `char_len`, for
+/// transports that escape the content.
+pub fn split_by(
+    content: &str,
+    format: &str,
+    max: usize,
+    char_len: &dyn Fn(char) -> usize,
+) -> Vec<String> {
+    if text_len(content, char_len) <= max {
+        return vec![content.to_string()];
+    }
+
+    let max_reserve = max / MAX_MARKUP_RESERVE_RATIO;
+    let mut reserve = 0;
+    loop {
+        let parts = split_with_reserve(content, format, max, reserve, char_len);
+        if reserve >= max_reserve || parts.iter().all(|part| text_len(part, char_len) <= max) {
+            return parts;
+        }
+        reserve = (reserve * 2).max(8).min(max_reserve);
+    }
+}
+
+/// Cuts `content` to at most `max` chars and marks the cut, keeping markup balanced.
+pub fn truncate(content: &str, format: &str, max: usize) -> String {
+    truncate_by(content, format, max, &unit_len)
+}
+
+/// Like `truncate`, with the length of each char given by `char_len`.
+pub fn truncate_by(
+    content: &str,
+    format: &str,
+    max: usize,
+    char_len: &dyn Fn(char) -> usize,
+) -> String {
+    if text_len(content, char_len) <= max {
+        return content.to_string();
+    }
+
+    let marker_len = text_len(TRUNCATION_MARKER, char_len);
+    let mut parts = split_by(
+        content,
+        format,
+        max.saturating_sub(marker_len).max(1),
+        char_len,
+    );
+    let mut head = parts.swap_remove(0);
+    head.push_str(TRUNCATION_MARKER);
+    head
+}
+
+/// Fits `content` into parts of at most `max` chars as the overflow option asks.
+pub fn fit(content: &str, format: &str, overflow: &str, max: usize) -> Vec<String> {
+    fit_by(content, format, overflow, max, &unit_len)
+}
+
+/// Like `fit`, with the length of each char given by `char_len`.
+pub fn fit_by(
+    content: &str,
+    format: &str,
+    overflow: &str,
+    max: usize,
+    char_len: &dyn Fn(char) -> usize,
+) -> Vec<String> {
+    match overflow {
+        message_overflow::TRUNCATE => vec![truncate_by(content, format, max, char_len)],
+        _ => split_by(content, format, max, char_len),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_bounded(parts: &[String], max: usize) {
+        for part in parts {
+            assert!(
+                text_len(part, &unit_len) <= max,
+                "{:?} exceeds {}",
+                part,
+                max
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_short_content() {
+        assert_eq!(split("hello", message_format::PLAIN, 5), vec!["hello"]);
+        assert_eq!(truncate("hello", message_format::PLAIN, 5), "hello");
+    }
+
+    #[test]
+    fn splits_on_lines_then_words() {
+        assert_eq!(
+            split("aaaa\nbbbb\ncccc", message_format::PLAIN, 10),
+            vec!["aaaa\nbbbb\n", "cccc"]
+        );
+        assert_eq!(
+            split("hello world foo", message_format::PLAIN, 8),
+            vec!["hello ", "world ", "foo"]
+        );
+    }
+
+    #[test]
+    fn splits_content_without_whitespace() {
+        let content = "a".repeat(25);
+        let parts = split(&content, message_format::PLAIN, 10);
+        assert_eq!(parts, vec!["a".repeat(10), "a".repeat(10), "a".repeat(5)]);
+
+        let content = "é".repeat(15);
+        let parts = split(&content, message_format::PLAIN, 10);
+        assert_eq!(parts, vec!["é".repeat(10), "é".repeat(5)]);
+    }
+
+    #[test]
+    fn plain_parts_add_up_to_content() {
+        let content = "The quick brown fox jumps over the lazy dog.\n".repeat(20);
+        for max in [7, 16, 50, 100] {
+            let parts = split(&content, message_format::PLAIN, max);
+            assert_bounded(&parts, max);
+            assert_eq!(parts.concat(), content);
+        }
+    }
+
+    #[test]
+    fn reopens_html_tags() {
+        let content = format!("<b>{}</b>", "word ".repeat(10));
+        let parts = split(&content, message_format::HTML, 20);
+        assert!(parts.len() > 1);
+        assert_bounded(&parts, 20);
+        for part in &parts {
+            assert!(part.starts_with("<b>"), "{:?}", part);
+            assert!(part.ends_with("</b>"), "{:?}", part);
+        }
+    }
+
+    #[test]
+    fn reopens_nested_html_tags_with_attributes() {
+        let content = format!(
+            "<a href=\"https://example.com\"><i>{}</i></a>",
+            "link text ".repeat(8)
+        );
+        let parts = split(&content, message_format::HTML, 60);
+        assert!(parts.len() > 1);
+        assert_bounded(&parts, 60);
+        for part in &parts {
+            assert!(part.starts_with("<a href=\"https://example.com\"><i>"));
+            assert!(part.ends_with("</i></a>"));
+        }
+    }
+
+    #[test]
+    fn does_not_cut_html_tags_or_entities() {
+        assert_eq!(
+            split("aaaaaaa&amp;bbbb", message_format::HTML, 10),
+            vec!["aaaaaaa", "&amp;bbbb"]
+        );
+        assert_eq!(
+            split("aaaaaaa<i>bbbb</i>", message_format::HTML, 11),
+            vec!["aaaaaaa", "<i>bbbb</i>"]
+        );
+        for max in [5, 8, 10] {
+            let parts = split(
+                "aaaaaaa<i>bbbb</i> <b>c&lt;d</b>",
+                message_format::HTML,
+                max,
+            );
+            for part in &parts {
+                assert!(!part.is_empty());
+                assert_eq!(part.matches('<').count(), part.matches('>').count());
+                assert_eq!(part.matches("<i>").count(), part.matches("</i>").count());
+                assert_eq!(part.matches("<b>").count(), part.matches("</b>").count());
+                assert!(!part.contains("&l") || part.contains("&lt;"), "{:?}", part);
+            }
+        }
+    }
+
+    #[test]
+    fn reopens_markdown_code_blocks() {
+        let content = format!("```rust\n{}```", "let a = 1;\n".repeat(10));
+        let parts = split(&content, message_format::MARKDOWN, 40);
+        assert!(parts.len() > 1);
+        assert_bounded(&parts, 40);
+        for part in &parts {
+            assert!(part.starts_with("```rust\n"), "{:?}", part);
+            assert!(part.ends_with("```"), "{:?}", part);
+        }
+    }
+
+    #[test]
+    fn does_not_cut_inline_markdown() {
+        assert_eq!(
+            split("see `some code here` now", message_format::MARKDOWN, 18),
+            vec!["see ", "`some code here` ", "now"]
+        );
+        assert_eq!(
+            split(
+                "go [the docs](https://x.io/a) now",
+                message_format::MARKDOWN,
+                28
+            ),
+            vec!["go ", "[the docs](https://x.io/a) ", "now"]
+        );
+    }
+
+    #[test]
+    fn truncates_with_marker() {
+        let content = "a".repeat(20);
+        let truncated = truncate(&content, message_format::PLAIN, 10);
+        assert_eq!(truncated, format!("{}{}", "a".repeat(9), TRUNCATION_MARKER));
+
+        let truncated = truncate("hello world foo", message_format::PLAIN, 10);
+        assert_eq!(truncated, format!("hello {}", TRUNCATION_MARKER));
+    }
+
+    #[test]
+    fn truncates_html_with_balanced_tags() {
+        let content = format!("<b>{}</b>", "x".repeat(20));
+        let truncated = truncate(&content, message_format::HTML, 10);
+        assert!(text_len(&truncated, &unit_len) <= 10, "{:?}", truncated);
+        assert!(truncated.starts_with("<b>"));
+        assert!(truncated.ends_with(&format!("</b>{}", TRUNCATION_MARKER)));
+    }
+
+    #[test]
+    fn splits_by_char_length() {
+        let escaped_len = |c| if c == '&' { 5 } else { 1 };
+        assert_eq!(
+            split_by("a&b&c&d", message_format::PLAIN, 6, &escaped_len),
+            vec!["a&", "b&", "c&", "d"]
+        );
+
+        let truncated = truncate_by("a&b&c&d", message_format::PLAIN, 7, &escaped_len);
+        assert_eq!(truncated, format!("a&{}", TRUNCATION_MARKER));
+    }
+
+    #[test]
+    fn fits_as_the_overflow_option_asks() {
+        let content = "word ".repeat(10);
+        let parts = fit(&content, message_format::PLAIN, message_overflow::SPLIT, 20);
+        assert_eq!(parts.len(), 3);
+        assert_bounded(&parts, 20);
+
+        let parts = fit(
+            &content,
+            message_format::PLAIN,
+            message_overflow::TRUNCATE,
+            20,
+        );
+        assert_eq!(parts.len(), 1);
+        assert_bounded(&parts, 20);
+        assert!(parts[0].ends_with(TRUNCATION_MARKER));
+    }
+}
@@ -1,49 +1,303 @@
+use super::DeliveryError;
+use crate::model::{self, message_format};
 use anyhow::Result;
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::time::Duration;
 
 pub struct Telegram {
+    client: surf::Client,
     uri: String,
 }
 
+/// Telegram rejects texts longer than this, counted after entities are parsed.
+const MAX_TEXT_LENGTH: usize = 4096;
+
 impl Telegram {
-    pub fn new(url: &str, access_token: &str) -> Self {
-        Telegram {
+    pub fn new(url: &str, access_token: &str, timeout: u64) -> Result<Self> {
+        let client = surf::Config::new()
+            .set_timeout(Some(Duration::from_secs(timeout)))
+            .try_into()?;
+
+        Ok(Telegram {
+            client,
             uri: format!("{}bot{}/sendMessage", url, access_token),
-        }
+        })
     }
 }
 
+#[derive(Serialize)]
+struct MessageEntity {
+    #[serde(rename = "type")]
+    entity_type: &'static str,
+    offset: usize,
+    length: usize,
+}
+
 #[derive(Serialize)]
 struct SendMessage {
     chat_id: String,
     text: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     parse_mode: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    entities: Vec<MessageEntity>,
+}
+
+#[derive(Default, Deserialize)]
+struct ResponseParameters {
+    retry_after: Option<f64>,
+    /// Set when the group was upgraded to a supergroup
+    migrate_to_chat_id: Option<i64>,
 }
 
 #[derive(Deserialize)]
 struct ResponsePayload {
     ok: bool,
+    error_code: Option<u16>,
     description: Option<String>,
+    parameters: Option<ResponseParameters>,
 }
 
-#[async_trait]
-impl super::Transport for Telegram {
-    async fn push(&self, chat: &str, title: &str, message: &str) -> Result<()> {
-        let text = if title.is_empty() {
-            format!("{}", message)
+/// Classifies an error reported by the Bot API. Missing chats and chats that
+/// blocked or removed the bot (403) are permanent.
+fn delivery_error(payload: ResponsePayload) -> anyhow::Error {
+    let description = payload
+        .description
+        .unwrap_or_else(|| "unknown error".to_string());
+    let params = payload.parameters.unwrap_or_default();
+    if let Some(chat_id) = params.migrate_to_chat_id {
+        return DeliveryError::ChatMigrated {
+            reason: description,
+            chat_id: chat_id.to_string(),
+        }
+        .into();
+    }
+
+    match payload.error_code {
+        Some(429) => DeliveryError::rate_limited(description, params.retry_after.unwrap_or(1.0)),
+        Some(403) => DeliveryError::permanent(description),
+        Some(400) if description.contains("chat not found") => {
+            DeliveryError::permanent(description)
+        }
+        _ => DeliveryError::Transient(description).into(),
+    }
+}
+
+/// Characters that must be escaped outside of entities in MarkdownV2.
+const MARKDOWN_V2_RESERVED: &str = "_*[]()~`>#+-=|{}.!\\";
+
+/// Escapes all MarkdownV2 reserved characters, so that `text` renders literally.
+pub fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if MARKDOWN_V2_RESERVED.contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Escapes text inside code entities and link urls, where only `reserved` and
+/// backslashes need to be escaped.
+fn escape_markdown_in(text: &[char], reserved: char) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text {
+        if *c == reserved || *c == '\\' {
+            escaped.push('\\');
+        }
+        escaped.push(*c);
+    }
+    escaped
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn find(chars: &[char], from: usize, pattern: &str) -> Option<usize> {
+    let pattern: Vec<char> = pattern.chars().collect();
+    (from..chars.len()).find(|i| chars[*i..].starts_with(&pattern))
+}
+
+/// Finds the `)` that closes a link url starting at `from`, parentheses inside
+/// the url must be balanced.
+fn find_closing_paren(chars: &[char], from: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in chars.iter().enumerate().skip(from) {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => return Some(i),
+            ')' => depth -= 1,
+            c if c.is_whitespace() => return None,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Converts common Markdown to MarkdownV2. Code, links, bold (`**`, `__`),
+/// italic (`*`, `_`) and strikethrough (`~~`) are kept, everything else is
+/// escaped. Emphasis markers that are not closed are escaped too.
+pub fn to_markdown_v2(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut segments: Vec<String> = Vec::new();
+    // Open emphasis per kind: the segment of the marker and the original marker
+    let mut open: [Option<(usize, &str)>; 3] = [None, None, None];
+
+    let mut i = 0;
+    while i < chars.len() {
+        let rest = &chars[i..];
+
+        if rest.starts_with(&['`', '`', '`']) {
+            if let Some(end) = find(&chars, i + 3, "```") {
+                segments.push(format!(
+                    "```{}```",
+                    escape_markdown_in(&chars[i + 3..end], '`')
+                ));
+                i = end + 3;
+                continue;
+            }
+        }
+
+        if chars[i] == '`' {
+            // Telegram rejects empty code entities
+            if let Some(end) = find(&chars, i + 1, "`").filter(|end| *end > i + 1) {
+                segments.push(format!("`{}`", escape_markdown_in(&chars[i + 1..end], '`')));
+                i = end + 1;
+                continue;
+            }
+        }
+
+        if chars[i] == '[' {
+            let link = find(&chars, i + 1, "](")
+                .and_then(|middle| find_closing_paren(&chars, middle + 2).map(|end| (middle, end)));
+            if let Some((middle, end)) = link {
+                let label: String = chars[i + 1..middle].iter().collect();
+                segments.push(format!(
+                    "[{}]({})",
+                    escape_markdown(&label),
+                    escape_markdown_in(&chars[middle + 2..end], ')')
+                ));
+                i = end + 1;
+                continue;
+            }
+        }
+
+        // Characters escaped by the author stay escaped
+        if chars[i] == '\\' && i + 1 < chars.len() && MARKDOWN_V2_RESERVED.contains(chars[i + 1]) {
+            segments.push(format!("\\{}", chars[i + 1]));
+            i += 2;
+            continue;
+        }
+
+        let emphasis = if rest.starts_with(&['*', '*']) {
+            Some(("**", "*", 0))
+        } else if rest.starts_with(&['_', '_']) {
+            Some(("__", "*", 0))
+        } else if rest.starts_with(&['~', '~']) {
+            Some(("~~", "~", 2))
+        } else if chars[i] == '*' {
+            Some(("*", "_", 1))
+        } else if chars[i] == '_' {
+            Some(("_", "_", 1))
         } else {
-            format!("[{}]\n\n{}", title, message)
+            None
         };
 
-        let data = &SendMessage {
-            text,
-            chat_id: chat.into(),
-            parse_mode: None,
+        if let Some((marker, replacement, kind)) = emphasis {
+            let len = marker.chars().count();
+            let prev = if i > 0 { Some(chars[i - 1]) } else { None };
+            let next = chars.get(i + len).copied();
+            let is_word = |c: Option<char>| c.is_some_and(char::is_alphanumeric);
+            let is_space = |c: Option<char>| c.is_none_or(char::is_whitespace);
+
+            match open[kind] {
+                Some((_, opened)) if opened == marker && !is_space(prev) && !is_word(next) => {
+                    segments.push(replacement.to_string());
+                    open[kind] = None;
+                    i += len;
+                    continue;
+                }
+                None if !is_space(next) && !is_word(prev) => {
+                    open[kind] = Some((segments.len(), marker));
+                    segments.push(replacement.to_string());
+                    i += len;
+                    continue;
+                }
+                _ => {}
+            }
+        }
+
+        segments.push(escape_markdown(&chars[i].to_string()));
+        i += 1;
+    }
+
+    for (segment, marker) in open.iter().flatten() {
+        segments[*segment] = escape_markdown(marker);
+    }
+
+    segments.concat()
+}
+
+fn plain_message(chat: &str, title: &str, content: &str) -> SendMessage {
+    let (text, entities) = if title.is_empty() {
+        (content.to_string(), Vec::new())
+    } else {
+        let bold = MessageEntity {
+            entity_type: "bold",
+            offset: 0,
+            // Offsets are counted in UTF-16 code units
+            length: title.encode_utf16().count(),
         };
+        (format!("{}\n\n{}", title, content), vec![bold])
+    };
 
-        let mut res = match surf::post(&self.uri).body_json(data) {
+    SendMessage {
+        chat_id: chat.into(),
+        text,
+        parse_mode: None,
+        entities,
+    }
+}
+
+fn formatted_message(chat: &str, title: &str, content: &str, format: &str) -> SendMessage {
+    let (text, parse_mode) = match format {
+        message_format::MARKDOWN => {
+            let content = to_markdown_v2(content);
+            match title.is_empty() {
+                true => (content, "MarkdownV2"),
+                false => (
+                    format!("*{}*\n\n{}", escape_markdown(title), content),
+                    "MarkdownV2",
+                ),
+            }
+        }
+        message_format::HTML => match title.is_empty() {
+            true => (content.to_string(), "HTML"),
+            false => (
+                format!("<b>{}</b>\n\n{}", escape_html(title), content),
+                "HTML",
+            ),
+        },
+        _ => return plain_message(chat, title, content),
+    };
+
+    SendMessage {
+        chat_id: chat.into(),
+        text,
+        parse_mode: Some(parse_mode.to_string()),
+        entities: Vec::new(),
+    }
+}
+
+impl Telegram {
+    async fn send(&self, data: &SendMessage) -> Result<()> {
+        let mut res = match self.client.post(&self.uri).body_json(data) {
             Ok(req) => match req.await {
                 Ok(res) => res,
                 Err(err) => return Err(err.into_inner()),
@@ -52,13 +306,160 @@ impl super::Transport for Telegram {
         };
 
         return match res.body_json::<ResponsePayload>().await {
-            Ok(ResponsePayload {
-                ok: false,
-                description: Some(description),
-                ..
-            }) => Err(anyhow::anyhow!(description)),
+            Ok(payload) if !payload.ok => Err(delivery_error(payload)),
             Ok(_) => Ok(()),
             Err(err) => Err(err.into_inner()),
         };
     }
+
+    /// Sends the message rendered in `format`. If Telegram cannot parse the
+    /// markup, the message is sent again as plain text.
+    pub async fn push_formatted(
+        &self,
+        chat: &str,
+        title: &str,
+        content: &str,
+        format: &str,
+    ) -> Result<()> {
+        let data = formatted_message(chat, title, content, format);
+        match self.send(&data).await {
+            Err(err)
+                if data.parse_mode.is_some()
+                    && err.to_string().contains("can't parse entities") =>
+            {
+                log::warn!("[Telegram] {}, falling back to plain text", err);
+                self.send(&plain_message(chat, title, content)).await
+            }
+            result => result,
+        }
+    }
+}
+
+#[async_trait]
+impl super::Transport for Telegram {
+    fn max_length(&self, title: &str) -> Option<usize> {
+        // The title is followed by a blank line
+        let overhead = if title.is_empty() {
+            0
+        } else {
+            title.chars().count() + 2
+        };
+        Some(MAX_TEXT_LENGTH.saturating_sub(overhead))
+    }
+
+    async fn push(&self, chat: &str, title: &str, message: &str) -> Result<()> {
+        self.push_formatted(chat, title, message, message_format::PLAIN)
+            .await
+    }
+
+    async fn deliver(&self, task: &model::Task, message: &model::Message) -> Result<()> {
+        self.push_formatted(
+            &task.chat_id,
+            &message.title,
+            &message.content,
+            &message.format,
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(body: &str) -> anyhow::Error {
+        delivery_error(serde_json::from_str(body).unwrap())
+    }
+
+    #[test]
+    fn classifies_errors() {
+        let err = error(
+            r#"{"ok":false,"error_code":400,"description":"Bad Request: group chat was upgraded to a supergroup chat","parameters":{"migrate_to_chat_id":-1001234567890}}"#,
+        );
+        match err.downcast_ref::<DeliveryError>() {
+            Some(DeliveryError::ChatMigrated { chat_id, .. }) => {
+                assert_eq!(chat_id, "-1001234567890")
+            }
+            _ => panic!("unexpected error {:?}", err),
+        }
+
+        let err = error(
+            r#"{"ok":false,"error_code":429,"description":"Too Many Requests","parameters":{"retry_after":3}}"#,
+        );
+        assert!(matches!(
+            err.downcast_ref::<DeliveryError>(),
+            Some(DeliveryError::RateLimited { retry_after: 3, .. })
+        ));
+
+        let err = error(
+            r#"{"ok":false,"error_code":403,"description":"Forbidden: bot was blocked by the user"}"#,
+        );
+        assert!(matches!(
+            err.downcast_ref::<DeliveryError>(),
+            Some(DeliveryError::Permanent(_))
+        ));
+    }
+
+    #[test]
+    fn escapes_reserved_characters() {
+        assert_eq!(escape_markdown("a.b-c!"), "a\\.b\\-c\\!");
+        assert_eq!(to_markdown_v2("1 + 1 = 2."), "1 \\+ 1 \\= 2\\.");
+    }
+
+    #[test]
+    fn keeps_code_blocks_and_inline_code() {
+        assert_eq!(
+            to_markdown_v2("```\nlet a = `b` \\ c.d;\n```"),
+            "```\nlet a = \\`b\\` \\\\ c.d;\n```"
+        );
+        assert_eq!(to_markdown_v2("run `a_b.c`"), "run `a_b.c`");
+        // Unclosed code is escaped
+        assert_eq!(to_markdown_v2("a `b"), "a \\`b");
+        assert_eq!(to_markdown_v2("```a"), "\\`\\`\\`a");
+    }
+
+    #[test]
+    fn keeps_links() {
+        assert_eq!(
+            to_markdown_v2("[a.b](https://example.com/x_y)"),
+            "[a\\.b](https://example.com/x_y)"
+        );
+        assert_eq!(
+            to_markdown_v2("[wiki](https://en.wikipedia.org/wiki/Rust_(language)) ok"),
+            "[wiki](https://en.wikipedia.org/wiki/Rust_(language\\)) ok"
+        );
+        // Not a link without the url part
+        assert_eq!(to_markdown_v2("[a] (b)"), "\\[a\\] \\(b\\)");
+    }
+
+    #[test]
+    fn converts_emphasis() {
+        assert_eq!(to_markdown_v2("**bold**"), "*bold*");
+        assert_eq!(to_markdown_v2("__bold__"), "*bold*");
+        assert_eq!(to_markdown_v2("*italic*"), "_italic_");
+        assert_eq!(to_markdown_v2("_italic_"), "_italic_");
+        assert_eq!(to_markdown_v2("~~gone~~"), "~gone~");
+    }
+
+    #[test]
+    fn converts_nested_emphasis() {
+        assert_eq!(to_markdown_v2("**bold _both_**"), "*bold _both_*");
+        assert_eq!(to_markdown_v2("*italic **both***"), "_italic *both*_");
+    }
+
+    #[test]
+    fn escapes_unclosed_emphasis() {
+        assert_eq!(to_markdown_v2("**bold"), "\\*\\*bold");
+        assert_eq!(to_markdown_v2("_a *b_"), "_a \\*b_");
+        assert_eq!(to_markdown_v2("a * b"), "a \\* b");
+        assert_eq!(to_markdown_v2("snake_case_name"), "snake\\_case\\_name");
+    }
+
+    #[test]
+    fn keeps_author_escapes() {
+        assert_eq!(to_markdown_v2("1\\. item"), "1\\. item");
+        assert_eq!(to_markdown_v2("\\*not bold\\*"), "\\*not bold\\*");
+        // A backslash before an ordinary character is escaped itself
+        assert_eq!(to_markdown_v2("C:\\dir"), "C:\\\\dir");
+    }
 }
@@ -0,0 +1,211 @@
+use super::DeliveryError;
+use crate::{config, model};
+use anyhow::{anyhow, Result};
+use async_std::net::ToSocketAddrs;
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use http_types::url::{Host, Url};
+use serde::Serialize;
+use sha2::Sha256;
+use std::convert::TryInto;
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::time::Duration;
+
+pub const SIGNATURE_HEADER: &str = "X-SPS-Signature";
+pub const TIMESTAMP_HEADER: &str = "X-SPS-Timestamp";
+
+/// Whether `ip` is reachable on the public internet.
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || octets[0] == 0
+                // Shared address space, 100.64.0.0/10
+                || (octets[0] == 100 && octets[1] & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_ip(IpAddr::V4(ip)),
+            None => {
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local())
+            }
+        },
+    }
+}
+
+/// Resolves the host of `url` and fails with a permanent `DeliveryError` unless
+/// all of its addresses are public or the host is in `allowed_hosts`, so that
+/// users cannot make the service call into its own network.
+pub async fn check_url(url: &str, allowed_hosts: &[String]) -> Result<()> {
+    let parsed = Url::parse(url)?;
+    let host = parsed
+        .host_str()
+        .ok_or_else(|| DeliveryError::permanent("webhook url has no host"))?;
+    if allowed_hosts
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(host))
+    {
+        return Ok(());
+    }
+
+    let addrs = match parsed.host() {
+        Some(Host::Ipv4(ip)) => vec![IpAddr::V4(ip)],
+        Some(Host::Ipv6(ip)) => vec![IpAddr::V6(ip)],
+        _ => {
+            let port = parsed.port_or_known_default().unwrap_or(80);
+            (host, port)
+                .to_socket_addrs()
+                .await?
+                .map(|addr| addr.ip())
+                .collect()
+        }
+    };
+
+    if addrs.is_empty() {
+        return Err(anyhow!("failed to resolve webhook host {}", host));
+    }
+    if let Some(ip) = addrs.into_iter().find(|ip| !is_public_ip(*ip)) {
+        return Err(DeliveryError::permanent(format!(
+            "webhook host {} resolves to non-public address {}",
+            host, ip
+        )));
+    }
+
+    Ok(())
+}
+
+pub struct Webhook {
+    client: surf::Client,
+    secret: String,
+    allowed_hosts: Vec<String>,
+}
+
+impl Webhook {
+    pub fn new(secret: &str, conf: &config::Webhook) -> Result<Self> {
+        let client = surf::Config::new()
+            .set_timeout(Some(Duration::from_secs(conf.timeout)))
+            .try_into()?;
+
+        Ok(Webhook {
+            client,
+            secret: String::from(secret),
+            allowed_hosts: conf.allowed_hosts.clone(),
+        })
+    }
+
+    /// Signs `{timestamp}.{body}` with HMAC-SHA256 and returns the hex digest.
+    fn sign(&self, timestamp: i64, body: &[u8]) -> Result<String> {
+        let mut mac: Hmac<Sha256> = Hmac::new_from_slice(self.secret.as_bytes())?;
+        mac.update(timestamp.to_string().as_bytes());
+        mac.update(b".");
+        mac.update(body);
+
+        let mut signature = String::from("sha256=");
+        for byte in mac.finalize().into_bytes() {
+            write!(signature, "{:02x}", byte)?;
+        }
+
+        Ok(signature)
+    }
+
+    async fn post(&self, url: &str, envelope: &Envelope<'_>) -> Result<()> {
+        // The host may resolve differently than when the url was bound
+        check_url(url, &self.allowed_hosts).await?;
+
+        let body = serde_json::to_vec(envelope)?;
+        let timestamp = chrono::Utc::now().timestamp();
+        let signature = self.sign(timestamp, &body)?;
+
+        let req = self
+            .client
+            .post(url)
+            .header("Content-Type", "application/json")
+            .header(TIMESTAMP_HEADER, timestamp.to_string())
+            .header(SIGNATURE_HEADER, signature)
+            .body(body);
+
+        let res = match req.await {
+            Ok(res) => res,
+            Err(err) => return Err(err.into_inner()),
+        };
+
+        let status = res.status();
+        if status.is_success() {
+            return Ok(());
+        }
+
+        if status == 429 {
+            let retry_after = res
+                .header("Retry-After")
+                .and_then(|value| value.as_str().parse::<f64>().ok())
+                .map_or(1.0, super::retry_after_secs);
+            return Err(DeliveryError::rate_limited(
+                "webhook rate limited",
+                retry_after,
+            ));
+        }
+
+        // Client errors other than a request timeout won't change on retry,
+        // e.g. the endpoint is gone (404/410) or rejects the payload
+        if status.is_client_error() && status != 408 {
+            return Err(DeliveryError::permanent(format!(
+                "webhook responded with status {}",
+                status
+            )));
+        }
+
+        Err(anyhow!("webhook responded with status {}", status))
+    }
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message_id: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    task_id: Option<i64>,
+    title: &'a str,
+    content: &'a str,
+    format: &'a str,
+    creation_time: chrono::DateTime<chrono::Utc>,
+}
+
+#[async_trait]
+impl super::Transport for Webhook {
+    async fn push(&self, chat: &str, title: &str, message: &str) -> Result<()> {
+        let envelope = Envelope {
+            message_id: None,
+            task_id: None,
+            title,
+            content: message,
+            format: model::message_format::PLAIN,
+            creation_time: chrono::Utc::now(),
+        };
+
+        self.post(chat, &envelope).await
+    }
+
+    async fn deliver(&self, task: &model::Task, message: &model::Message) -> Result<()> {
+        let envelope = Envelope {
+            message_id: Some(message.id),
+            task_id: Some(task.id),
+            title: &message.title,
+            content: &message.content,
+            format: &message.format,
+            creation_time: message.creation_time,
+        };
+
+        self.post(&task.chat_id, &envelope).await
+    }
+}
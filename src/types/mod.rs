@@ -1,15 +1,47 @@
+use chrono::{DateTime, TimeZone, Utc};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Deserialize)]
 pub struct AuthRequest {
-    pub address: String,
-    pub timestamp: i64,
+    /// Sign-In with Ethereum (EIP-4361) message
+    pub message: String,
     pub signature: String,
 }
 
+#[derive(Debug, Serialize)]
+pub struct NonceResponse {
+    pub nonce: String,
+}
+
 #[derive(Debug, Serialize)]
 pub struct AuthResponse {
     pub access_token: String,
+    /// Lifetime of the access token in seconds
+    pub expires_in: i64,
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RefreshTokenRequest {
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct LogoutRequest {
+    /// Refresh token to revoke along with the access token
+    pub refresh_token: Option<String>,
+}
+
+/// Claims of the JWT access token.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    /// Wallet address of the user
+    pub sub: String,
+    pub iss: String,
+    pub aud: String,
+    pub jti: String,
+    pub iat: i64,
+    pub exp: i64,
 }
 
 #[derive(Debug, Serialize)]
@@ -29,13 +61,200 @@ pub struct GetMeResponse {
     pub transports: Vec<Transport>,
 }
 
+#[derive(Debug, Default, Deserialize)]
+pub struct RotateProjectIdRequest {
+    /// Seconds the replaced keys stay valid, they are revoked immediately if omitted
+    pub grace_period: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProjectIdItem {
+    pub project_id: String,
+    /// Whether this is the current key, the others expire after their grace period
+    pub current: bool,
+    pub creation_time: chrono::DateTime<chrono::Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_used_time: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expire_time: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SaveProjectRequest {
+    pub name: String,
+    /// Transport types the project delivers to, all transports if omitted
+    pub transports: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProjectItem {
+    pub id: i64,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transports: Option<Vec<String>>,
+    pub project_ids: Vec<ProjectIdItem>,
+    /// Whether push requests must be signed
+    pub signed: bool,
+    pub creation_time: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetProjectSigningRequest {
+    pub enabled: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SetProjectSigningResponse {
+    pub enabled: bool,
+    /// Secret to sign push requests with, only returned when it is issued
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ListProjectsResponse {
+    pub projects: Vec<ProjectItem>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BindEmailRequest {
+    pub address: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VerifyEmailRequest {
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BindWebhookRequest {
+    pub url: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BindWebhookResponse {
+    pub url: String,
+    pub secret: String,
+}
+
+/// Absolute point in time, either as unix timestamp or RFC 3339 string
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Timestamp {
+    Unix(i64),
+    Text(String),
+}
+
+impl Timestamp {
+    pub fn to_datetime(&self) -> Option<DateTime<Utc>> {
+        let text = match self {
+            Timestamp::Unix(ts) => return Utc.timestamp_opt(*ts, 0).single(),
+            Timestamp::Text(text) => text,
+        };
+
+        // Query strings carry unix timestamps as text as well
+        match text.parse::<i64>() {
+            Ok(ts) => Utc.timestamp_opt(ts, 0).single(),
+            Err(_) => DateTime::parse_from_rfc3339(text)
+                .ok()
+                .map(|time| time.with_timezone(&Utc)),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct PushMessageRequest {
     pub title: String,
     pub content: String,
+    /// Markup of the content: plain (default), markdown or html
+    pub format: Option<String>,
+    /// Content too long for a transport is split (default) or truncated
+    pub overflow: Option<String>,
+    pub send_at: Option<Timestamp>,
+    /// Delay in seconds
+    pub delay: Option<i64>,
+    /// Alternative to the `Idempotency-Key` header
+    pub idempotency_key: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
 pub struct PushMessageResponse {
     pub status: String,
+    pub message_id: i64,
+    pub task_ids: Vec<i64>,
+}
+
+/// Result of a batch item, shaped like the response envelope of a single push.
+#[derive(Debug, Serialize)]
+pub struct PushBatchItem {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Whether the result is that of an earlier push with the same idempotency key
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub replayed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<PushMessageResponse>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PushBatchResponse {
+    pub results: Vec<PushBatchItem>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TaskStatus {
+    pub id: i64,
+    #[serde(rename = "type")]
+    pub transport_type: String,
+    pub state: String,
+    pub retry_count: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_attempt_time: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delivered_time: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GetMessageResponse {
+    pub id: i64,
+    pub title: String,
+    pub content: String,
+    pub format: String,
+    pub send_time: chrono::DateTime<chrono::Utc>,
+    pub creation_time: chrono::DateTime<chrono::Utc>,
+    pub tasks: Vec<TaskStatus>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListMessagesRequest {
+    /// Only return messages with an id lower than the cursor
+    pub cursor: Option<i64>,
+    pub limit: Option<i64>,
+    pub from: Option<Timestamp>,
+    pub to: Option<Timestamp>,
+    /// Only return messages with at least one task in this state
+    pub state: Option<String>,
+    /// Full-text search over title and content
+    pub q: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MessageItem {
+    pub id: i64,
+    pub title: String,
+    pub content: String,
+    pub send_time: chrono::DateTime<chrono::Utc>,
+    pub creation_time: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ListMessagesResponse {
+    pub messages: Vec<MessageItem>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<i64>,
 }